@@ -1,23 +1,83 @@
 use anyhow::anyhow;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use colored::Colorize;
-use oauth2::basic::BasicClient;
-use oauth2::reqwest::async_http_client;
+use oauth2::basic::{
+    BasicErrorResponse, BasicRevocationErrorResponse, BasicTokenIntrospectionResponse,
+    BasicTokenType,
+};
 use oauth2::{
-    AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge,
-    PkceCodeVerifier, RedirectUrl, TokenResponse,
+    AuthType, AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken,
+    ErrorResponseType, ExtraTokenFields, HttpRequest, HttpResponse, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, RefreshToken, RequestTokenError, RevocationUrl, Scope,
+    StandardErrorResponse, StandardRevocableToken, StandardTokenResponse, TokenResponse,
 };
+use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::Url;
 use serde::de::Visitor;
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::client_auth::{client_assertion, ClientAuthMethod};
+use crate::dpop::DpopKey;
 use crate::strategies::Strategy;
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct StateCode {
-    state: Option<CsrfToken>,
-    code: Option<String>,
+    pub(crate) state: Option<CsrfToken>,
+    pub(crate) code: Option<String>,
+    // Any other callback parameters, i.e `openid.*` fields for strategies that are not OAuth 2.0 providers.
+    #[serde(flatten)]
+    pub(crate) params: HashMap<String, String>,
+}
+
+/// Token response fields beyond the RFC 6749 ones, i.e Salesforce's `instance_url` and `id`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
+#[serde(transparent)]
+pub struct TokenFields(HashMap<String, serde_json::Value>);
+
+impl ExtraTokenFields for TokenFields {}
+
+impl TokenFields {
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.0.get(key)
+    }
+}
+
+pub type PassportTokenResponse = StandardTokenResponse<TokenFields, BasicTokenType>;
+
+pub type PassportClient = Client<
+    BasicErrorResponse,
+    PassportTokenResponse,
+    BasicTokenType,
+    BasicTokenIntrospectionResponse,
+    StandardRevocableToken,
+    BasicRevocationErrorResponse,
+>;
+
+/// Device authorization response (RFC 8628) returned by `start_device_flow`.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct DeviceAuthorization {
+    #[serde(default)]
+    pub provider: String,
+    pub device_code: String,
+    pub user_code: String,
+    // Microsoft's v1 endpoints still send `verification_url`.
+    #[serde(alias = "verification_url")]
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    #[serde(default = "DeviceAuthorization::default_interval")]
+    pub interval: u64,
+}
+
+impl DeviceAuthorization {
+    fn default_interval() -> u64 {
+        5
+    }
 }
 
 pub enum PassportResponse {
@@ -61,30 +121,30 @@ impl<'de> serde::Deserialize<'de> for Verifier {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct PassPortBasicClient {
     types: HashMap<String, Arc<dyn Strategy>>,
-    clients: HashMap<String, BasicClient>,
+    clients: HashMap<String, PassportClient>,
     current: String,
     sessions: HashMap<String, String>,
+    // `request_uri` and its expiry of each pushed login, by state.
+    pushed: HashMap<String, (String, i64)>,
+    // DPoP key of each login, by state.
+    dpop_keys: HashMap<String, DpopKey>,
+    // Client credentials tokens (with their expiry) by provider and scopes. An entry stays locked while its token is
+    // fetched, so concurrent callers wait for that request instead of sending their own.
+    tokens: Arc<Mutex<HashMap<String, CachedToken>>>,
 }
 
-impl Default for PassPortBasicClient {
-    fn default() -> Self {
-        Self {
-            types: HashMap::new(),
-            clients: HashMap::new(),
-            current: String::new(),
-            sessions: HashMap::new(),
-        }
-    }
-}
+type CachedToken = Arc<tokio::sync::Mutex<Option<(PassportTokenResponse, tokio::time::Instant)>>>;
 
 unsafe impl Send for PassPortBasicClient {}
 unsafe impl Sync for PassPortBasicClient {}
 
 impl PassPortBasicClient {
     const USER_AGENT: &'static str = "Mozilla/5.0 (iPhone; CPU iPhone OS 13_2_3 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/13.0.3 Mobile/15E148 Safari/604.1";
+    // Cached tokens are renewed this long before they expire.
+    const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(60);
     pub fn authenticate(&mut self, state: &str) {
         self.current.clear();
         self.current.push_str(state);
@@ -99,28 +159,77 @@ impl PassPortBasicClient {
             Ok(uri) => uri,
             Err(err) => panic!("{}{}", "Invalid Redirect URL: ".bold().red(), err),
         };
-        let client = BasicClient::new(
+        // Only the secret based methods hand the secret to oauth2, the others send the client id in the body and add
+        // their assertion (if any) to each request.
+        let method = typ.client_auth_method();
+        let client_secret = if method.sends_secret() {
+            Some(ClientSecret::new(typ.client_secret().to_string()))
+        } else {
+            None
+        };
+        let auth_type = match method {
+            ClientAuthMethod::ClientSecretBasic => AuthType::BasicAuth,
+            _ => AuthType::RequestBody,
+        };
+        let mut client = PassportClient::new(
             ClientId::new(typ.client_id().to_string()),
-            Some(ClientSecret::new(typ.client_secret().to_string())),
+            client_secret,
             auth,
             typ.token_url(),
         )
-        .set_redirect_uri(redirect_url);
+        .set_redirect_uri(redirect_url)
+        .set_auth_type(auth_type);
+        if let Some(revocation_url) = typ.revocation_url() {
+            client = match RevocationUrl::new(revocation_url) {
+                Ok(url) => client.set_revocation_uri(url),
+                Err(err) => panic!("{}{}", "Invalid Revocation URL: ".bold().red(), err),
+            };
+        }
         self.clients.insert(kind.to_string(), client);
         self.types.insert(kind.to_string(), Arc::new(typ));
     }
 
+    /// Replaces the redirect url of a registered strategy, i.e the loopback port of a native app. Strategies that build
+    /// their own redirect (Steam, WeChat, AT Protocol) keep the one they were created with.
+    pub fn set_redirect_url(&mut self, kind: &str, redirect_url: &str) -> anyhow::Result<()> {
+        let client = self
+            .clients
+            .get_mut(kind)
+            .ok_or_else(|| anyhow!("No strategy registered as {}", kind))?;
+        *client = client
+            .clone()
+            .set_redirect_uri(RedirectUrl::new(redirect_url.to_string())?);
+        Ok(())
+    }
+
     pub fn generate_redirect_url(&mut self) -> String {
         let (pkce_challenge, verifier) = PkceCodeChallenge::new_random_sha256();
         let typ = self.types.get(&self.current);
         let scopes = typ.unwrap().scopes();
 
-        let client = self.clients.get(&self.current).unwrap();
-        let (auth_url, csrf_token) = client
-            .authorize_url(CsrfToken::new_random)
-            .set_pkce_challenge(pkce_challenge)
-            .add_scopes(scopes)
-            .url();
+        let csrf_token = CsrfToken::new_random();
+        let auth_url = match typ.unwrap().authorize_redirect(&csrf_token) {
+            Some(url) => url,
+            None => {
+                let client = self.clients.get(&self.current).unwrap();
+                let mut request = client
+                    .authorize_url(|| csrf_token.clone())
+                    .add_scopes(scopes);
+                for (name, value) in typ.unwrap().authorize_params() {
+                    request = request.add_extra_param(name, value);
+                }
+                if typ.unwrap().pkce() {
+                    request = request.set_pkce_challenge(pkce_challenge);
+                }
+                // The code is bound to the key through its thumbprint, so only this login can redeem it.
+                if typ.unwrap().dpop() {
+                    let dpop = DpopKey::generate();
+                    request = request.add_extra_param("dpop_jkt", dpop.thumbprint());
+                    self.dpop_keys.insert(csrf_token.secret().to_string(), dpop);
+                }
+                request.url().0
+            }
+        };
 
         let data = Verifier(verifier);
         let json_value = serde_json::to_string(&data).unwrap();
@@ -131,63 +240,161 @@ impl PassPortBasicClient {
         auth_url.to_string()
     }
 
+    /// Async counterpart of `generate_redirect_url`, for strategies that resolve the user's server before redirecting
+    /// (i.e `AtprotoStrategy`) or push the authorization request. `login_hint` is the handle or account the user typed in.
+    pub async fn generate_redirect_url_async(
+        &mut self,
+        login_hint: Option<&str>,
+    ) -> anyhow::Result<String> {
+        let strategy = Arc::clone(self.types.get(&self.current).unwrap());
+        let csrf_token = CsrfToken::new_random();
+        match strategy.resolve_redirect(&csrf_token, login_hint).await {
+            Some(url) => {
+                let url = url?;
+                // The strategy keeps its own verifier, the session only has to know the state.
+                let (_, verifier) = PkceCodeChallenge::new_random_sha256();
+                self.sessions.insert(
+                    csrf_token.secret().to_string(),
+                    serde_json::to_string(&Verifier(verifier))?,
+                );
+                Ok(url.to_string())
+            }
+            None => match strategy.pushed_authorization_url() {
+                Some(endpoint) => self.push_authorization_request(&endpoint).await,
+                None => Ok(self.generate_redirect_url()),
+            },
+        }
+    }
+
+    /// Unix time a url returned by `generate_redirect_url_async` stops working, for pushed authorization requests.
+    /// Providers only keep the `request_uri` for a minute or so, generate a fresh url if the user is sent there after
+    /// it. Callbacks of a login whose pushed request expired end at the failure redirect.
+    pub fn redirect_url_expires_at(&self, url: &str) -> Option<i64> {
+        let url = url.parse::<Url>().ok()?;
+        let request_uri = url.query_pairs().find(|(key, _)| key == "request_uri")?.1;
+        self.pushed
+            .values()
+            .find(|(pushed, _)| *pushed == request_uri)
+            .map(|(_, expires_at)| *expires_at)
+    }
+
+    // RFC 9126, the parameters `generate_redirect_url` puts in the query are posted to the provider, which hands back a
+    // short lived `request_uri` standing in for them.
+    async fn push_authorization_request(&mut self, endpoint: &str) -> anyhow::Result<String> {
+        let strategy = Arc::clone(self.types.get(&self.current).unwrap());
+        let mut url = self.generate_redirect_url().parse::<Url>()?;
+        let mut params = url.query_pairs().into_owned().collect::<Vec<_>>();
+        let state = params
+            .iter()
+            .find(|(key, _)| key == "state")
+            .map(|(_, state)| state.clone())
+            .unwrap_or_default();
+        let request = token_client(strategy.as_ref())?
+            .post(endpoint)
+            .headers(strategy.token_headers());
+        let request = authenticate_client(strategy.as_ref(), request, &mut params, endpoint)?;
+        let response = request.form(&params).send().await?;
+        if !response.status().is_success() {
+            self.sessions.remove(&state);
+            self.dpop_keys.remove(&state);
+            anyhow::bail!(response.text().await?)
+        }
+        let pushed = response.json::<serde_json::Value>().await?;
+        let request_uri = match pushed["request_uri"].as_str() {
+            Some(request_uri) => request_uri,
+            None => {
+                self.sessions.remove(&state);
+                self.dpop_keys.remove(&state);
+                anyhow::bail!("Pushed authorization response without request_uri")
+            }
+        };
+        let expires_in = pushed["expires_in"].as_i64().unwrap_or(60);
+        self.pushed.insert(
+            state,
+            (
+                request_uri.to_string(),
+                chrono::Utc::now().timestamp() + expires_in,
+            ),
+        );
+        url.set_query(None);
+        url.query_pairs_mut()
+            .append_pair("client_id", &strategy.client_id())
+            .append_pair("request_uri", request_uri);
+        Ok(url.to_string())
+    }
+
     pub async fn get_profile(&mut self, statecode: StateCode) -> anyhow::Result<PassportResponse> {
         // Adding check for StateCode for handling errors incase the authorization is cancelled by the user or csrf and code challenge mismatch.
         // This mean that unlike the previous versions, passport response enum is returned. It can either be a failure_redirect or json profile.
+        let strategy = Arc::clone(self.types.get(&self.current).unwrap());
+        let mut dpop = None;
+        if let Some(state) = statecode.state.as_ref() {
+            if !self.sessions.contains_key(state.secret()) {
+                return Ok(PassportResponse::FailureRedirect(
+                    strategy.failure_redirect(),
+                ));
+            }
+            dpop = self.dpop_keys.remove(state.secret());
+            if let Some((_, expires_at)) = self.pushed.remove(state.secret()) {
+                if chrono::Utc::now().timestamp() > expires_at {
+                    self.sessions.remove(state.secret());
+                    return Ok(PassportResponse::FailureRedirect(
+                        strategy.failure_redirect(),
+                    ));
+                }
+            }
+        }
+        // Strategies that are not OAuth 2.0 providers (i.e OpenID 2.0) verify the callback themselves.
+        if let Some(response) = strategy.verify_callback(&statecode).await {
+            if let Some(state) = statecode.state.as_ref() {
+                self.sessions.remove(state.secret());
+            }
+            return response;
+        }
         if statecode.state.is_none() || statecode.code.is_none() {
             return Ok(PassportResponse::FailureRedirect(
-                self.types.get(&self.current).unwrap().failure_redirect(),
+                strategy.failure_redirect_for(&statecode),
             ));
         }
+        // i.e Shopify signs the callback query, it is checked before the code is exchanged.
+        strategy.check_callback(&statecode)?;
         match self
             .sessions
             .get(statecode.state.as_ref().unwrap().secret())
         {
             Some(verifier) => {
-                let json_pkce: Verifier = serde_json::from_str(&verifier).unwrap();
+                let json_pkce: Verifier = serde_json::from_str(verifier).unwrap();
                 let clients = self.clients.get(&self.current).unwrap();
-                match clients
-                    .clone()
-                    .exchange_code(AuthorizationCode::new(statecode.code.unwrap().clone()))
-                    .set_pkce_verifier(json_pkce.0)
-                    .request_async(async_http_client)
+                let token_params = strategy.token_params(&statecode);
+                let mut request =
+                    clients.exchange_code(AuthorizationCode::new(statecode.code.unwrap().clone()));
+                for (name, value) in token_params
+                    .into_iter()
+                    .chain(token_assertion(strategy.as_ref())?)
+                {
+                    request = request.add_extra_param(name, value);
+                }
+                if strategy.pkce() {
+                    request = request.set_pkce_verifier(json_pkce.0);
+                }
+                let http = token_client(strategy.as_ref())?;
+                match request
+                    .request_async(|request| {
+                        token_http_client(request, strategy.token_headers(), dpop.clone(), http)
+                    })
                     .await
                 {
                     Ok(access_token) => {
                         self.sessions.remove(statecode.state.unwrap().secret());
-                        let response = reqwest::Client::new()
-                            .get(self.types.get(&self.current).unwrap().request_uri())
-                            .header(
-                                reqwest::header::AUTHORIZATION,
-                                format!("Bearer {}", access_token.access_token().secret()),
-                            )
-                            .header(reqwest::header::USER_AGENT, Self::USER_AGENT)
-                            .send()
-                            .await
-                            .unwrap();
-                        if response.status().is_success() {
-                            response
-                                .json::<serde_json::Value>()
-                                .await
-                                .map_err(|error| anyhow!(error))
-                                .map(|mut profile| {
-                                    profile["access_token"] =
-                                        serde_json::json!(access_token.access_token().secret());
-                                    profile["refresh_token"] = match access_token.refresh_token() {
-                                        Some(token) => {
-                                            serde_json::json!(Some::<String>(token.secret().into()))
-                                        }
-                                        None => serde_json::json!(None::<String>),
-                                    };
-                                    PassportResponse::Profile(profile)
-                                })
-                        } else {
-                            anyhow::bail!(response.text().await.unwrap())
-                        }
+                        check_certificate_binding(strategy.as_ref(), &access_token)?;
+                        let profile =
+                            Self::build_profile(strategy.as_ref(), &access_token, dpop.as_ref())
+                                .await?;
+                        Ok(PassportResponse::Profile(profile))
                     }
                     Err(err) => {
                         self.sessions.remove(statecode.state.unwrap().secret());
-                        anyhow::bail!(err.to_string())
+                        anyhow::bail!(token_error(err))
                     }
                 }
             }
@@ -197,4 +404,1116 @@ impl PassPortBasicClient {
             )),
         }
     }
+
+    // The profile returned for a token, whichever grant it came from.
+    async fn build_profile(
+        strategy: &dyn Strategy,
+        token: &PassportTokenResponse,
+        dpop: Option<&DpopKey>,
+    ) -> anyhow::Result<serde_json::Value> {
+        // Some providers (i.e Notion, Strava) put the user in the token response itself.
+        let profile = match strategy.token_profile(token) {
+            Some(profile) => profile?,
+            None => Self::fetch_profile(strategy, token, dpop).await?,
+        };
+        let profile = strategy.map_profile(profile)?;
+        let mut profile = strategy.extend_profile(profile, token).await?;
+        profile["access_token"] = serde_json::json!(token.access_token().secret());
+        profile["refresh_token"] = match token.refresh_token() {
+            Some(token) => serde_json::json!(Some::<String>(token.secret().into())),
+            None => serde_json::json!(None::<String>),
+        };
+        // The tokens are useless without the key they are bound to.
+        if let Some(dpop) = dpop.filter(|_| dpop_bound(token)) {
+            profile["dpop_key"] = dpop.to_jwk();
+        }
+        Ok(profile)
+    }
+
+    /// Starts the device authorization grant (RFC 8628) with a registered strategy that supports it (i.e Github,
+    /// Microsoft, Keycloak). Show the user the `user_code` and `verification_uri`, then wait on `poll_device_flow`.
+    /// Strategies without a client secret are treated as public clients.
+    pub async fn start_device_flow(&self, provider: &str) -> anyhow::Result<DeviceAuthorization> {
+        let strategy = self
+            .types
+            .get(provider)
+            .ok_or_else(|| anyhow!("No strategy registered as {}", provider))?;
+        let endpoint = strategy
+            .device_authorization_url()
+            .ok_or_else(|| anyhow!("{} does not support the device flow", provider))?;
+        let scopes = strategy
+            .scopes()
+            .iter()
+            .map(|scope| scope.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut params = vec![
+            (String::from("client_id"), strategy.client_id()),
+            (String::from("scope"), scopes),
+        ];
+        let request = token_client(strategy.as_ref())?
+            .post(&endpoint)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .headers(strategy.token_headers());
+        let request = authenticate_client(strategy.as_ref(), request, &mut params, &endpoint)?;
+        let response = request.form(&params).send().await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        let mut device = response.json::<DeviceAuthorization>().await?;
+        device.provider = provider.to_string();
+        Ok(device)
+    }
+
+    /// Polls the token endpoint until the user finished (or refused) the device flow started by `start_device_flow`.
+    /// Ends like `get_profile`, a refusal gives the strategy's failure redirect.
+    pub fn poll_device_flow(
+        &self,
+        device: DeviceAuthorization,
+    ) -> impl Future<Output = anyhow::Result<PassportResponse>> + Send + 'static {
+        let strategy = self.types.get(&device.provider).map(Arc::clone);
+        async move {
+            let strategy =
+                strategy.ok_or_else(|| anyhow!("No strategy registered as {}", device.provider))?;
+            let token_url = strategy
+                .token_url()
+                .ok_or_else(|| anyhow!("{} has no token endpoint", device.provider))?;
+            let http = token_client(strategy.as_ref())?;
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(device.expires_in);
+            let mut interval = device.interval.max(1);
+            loop {
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                if tokio::time::Instant::now() >= deadline {
+                    anyhow::bail!("The device code expired before the user signed in")
+                }
+                let mut params = vec![
+                    (
+                        String::from("grant_type"),
+                        String::from("urn:ietf:params:oauth:grant-type:device_code"),
+                    ),
+                    (String::from("device_code"), device.device_code.clone()),
+                    (String::from("client_id"), strategy.client_id()),
+                ];
+                let request = http
+                    .post(token_url.as_str())
+                    .header(reqwest::header::ACCEPT, "application/json")
+                    .headers(strategy.token_headers());
+                let request = authenticate_client(
+                    strategy.as_ref(),
+                    request,
+                    &mut params,
+                    token_url.as_str(),
+                )?;
+                let response = request.form(&params).send().await?;
+                // Github answers pending polls with `200 OK` too, the body tells them apart.
+                let mut json = response.json::<serde_json::Value>().await?;
+                match json["error"].as_str() {
+                    Some("authorization_pending") => continue,
+                    // RFC 8628 asks for 5 more seconds, some providers (i.e Github) say which interval to use.
+                    Some("slow_down") => {
+                        interval = json["interval"]
+                            .as_u64()
+                            .unwrap_or_default()
+                            .max(interval + 5)
+                    }
+                    Some("expired_token") => {
+                        anyhow::bail!("The device code expired before the user signed in")
+                    }
+                    // Microsoft reports a refusal as `authorization_declined`.
+                    Some("access_denied") | Some("authorization_declined") => {
+                        return Ok(PassportResponse::FailureRedirect(
+                            strategy.failure_redirect(),
+                        ))
+                    }
+                    Some("bad_verification_code") => {
+                        anyhow::bail!("The provider does not recognize the device code")
+                    }
+                    Some(error) => match json["error_description"].as_str() {
+                        Some(description) => anyhow::bail!("{}: {}", error, description),
+                        None => anyhow::bail!(error.to_string()),
+                    },
+                    None => {
+                        normalize_token_response(&mut json);
+                        let token = serde_json::from_value::<PassportTokenResponse>(json)?;
+                        check_certificate_binding(strategy.as_ref(), &token)?;
+                        let profile = Self::build_profile(strategy.as_ref(), &token, None).await?;
+                        return Ok(PassportResponse::Profile(profile));
+                    }
+                }
+            }
+        }
+    }
+
+    /// App-only token (client credentials grant) of a registered strategy, i.e Microsoft Graph with
+    /// `https://graph.microsoft.com/.default` (the `MicrosoftStrategy` must be set to a `tenant`). Tokens are reused until shortly before they expire, and concurrent calls
+    /// for the same provider and scopes share one request. `expires_in` of a reused token is the one it was issued with.
+    pub async fn client_credentials(
+        &self,
+        provider: &str,
+        scopes: Vec<&str>,
+    ) -> anyhow::Result<PassportTokenResponse> {
+        let (strategy, client) = match (self.types.get(provider), self.clients.get(provider)) {
+            (Some(strategy), Some(client)) => (strategy, client),
+            _ => anyhow::bail!("No strategy registered as {}", provider),
+        };
+        let mut scopes = scopes.iter().map(ToString::to_string).collect::<Vec<_>>();
+        scopes.sort();
+        scopes.dedup();
+        let key = format!("{} {}", provider, scopes.join(" "));
+        let entry = Arc::clone(self.tokens.lock().unwrap().entry(key).or_default());
+        let mut cached = entry.lock().await;
+        if let Some((token, expires_at)) = cached.as_ref() {
+            if tokio::time::Instant::now() + Self::TOKEN_EXPIRY_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+        let mut request = client
+            .exchange_client_credentials()
+            .add_scopes(scopes.into_iter().map(Scope::new));
+        for (name, value) in token_assertion(strategy.as_ref())? {
+            request = request.add_extra_param(name, value);
+        }
+        let http = token_client(strategy.as_ref())?;
+        let token = request
+            .request_async(|request| {
+                token_http_client(request, strategy.token_headers(), None, http)
+            })
+            .await
+            .map_err(|err| anyhow!(token_error(err)))?;
+        check_certificate_binding(strategy.as_ref(), &token)?;
+        // Tokens without an expiry are not cached, there is no telling when they stop working.
+        *cached = token
+            .expires_in()
+            .map(|expires_in| (token.clone(), tokio::time::Instant::now() + expires_in));
+        Ok(token)
+    }
+
+    /// Exchanges a refresh token issued through the current strategy. Tokens bound to a DPoP key need it back, restore
+    /// it from the profile's `dpop_key` with `DpopKey::from_jwk`.
+    pub async fn refresh_token(
+        &self,
+        refresh_token: &str,
+        dpop: Option<&DpopKey>,
+    ) -> anyhow::Result<PassportTokenResponse> {
+        let strategy = self.types.get(&self.current).unwrap();
+        let client = self.clients.get(&self.current).unwrap();
+        let refresh_token = RefreshToken::new(refresh_token.to_string());
+        let mut request = client.exchange_refresh_token(&refresh_token);
+        for (name, value) in token_assertion(strategy.as_ref())? {
+            request = request.add_extra_param(name, value);
+        }
+        let http = token_client(strategy.as_ref())?;
+        let token = request
+            .request_async(|request| {
+                token_http_client(request, strategy.token_headers(), dpop.cloned(), http)
+            })
+            .await
+            .map_err(|err| anyhow!(token_error(err)))?;
+        check_certificate_binding(strategy.as_ref(), &token)?;
+        Ok(token)
+    }
+
+    /// Revokes an access or refresh token (RFC 7009) at the current strategy's revocation endpoint.
+    pub async fn revoke_token(&self, token: StandardRevocableToken) -> anyhow::Result<()> {
+        let strategy = self.types.get(&self.current).unwrap();
+        let client = self.clients.get(&self.current).unwrap();
+        let revocation_url = strategy
+            .revocation_url()
+            .ok_or_else(|| anyhow!("{} has no revocation endpoint", self.current))?;
+        let mut request = client.revoke_token(token)?;
+        for (name, value) in client_assertion(strategy.as_ref(), &revocation_url)? {
+            request = request.add_extra_param(name, value);
+        }
+        let http = token_client(strategy.as_ref())?;
+        request
+            .request_async(|request| {
+                token_http_client(request, strategy.token_headers(), None, http)
+            })
+            .await
+            .map_err(|err| anyhow!(token_error(err)))
+    }
+
+    async fn fetch_profile(
+        strategy: &dyn Strategy,
+        token: &PassportTokenResponse,
+        dpop: Option<&DpopKey>,
+    ) -> anyhow::Result<serde_json::Value> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            reqwest::header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token.access_token().secret()))?,
+        );
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            HeaderValue::from_static(Self::USER_AGENT),
+        );
+        // Strategy headers replace the defaults, i.e a provider mandated User-Agent.
+        headers.extend(strategy.profile_headers());
+        let mut client = reqwest::Client::builder();
+        if let Some(certificate) = strategy.client_certificate() {
+            client = certificate.configure(client);
+        }
+        let client = client.build()?;
+        let request = client
+            .request(strategy.profile_method(), strategy.profile_uri(token)?)
+            .query(&strategy.profile_params())
+            .headers(headers)
+            .build()?;
+        // A provider that ignored the DPoP key issued a plain bearer token.
+        let response = match dpop.filter(|_| dpop_bound(token)) {
+            Some(dpop) => {
+                dpop.send_with(&client, request, Some(token.access_token().secret()))
+                    .await?
+            }
+            None => client.execute(request).await?,
+        };
+        if response.status().is_success() {
+            response
+                .json::<serde_json::Value>()
+                .await
+                .map_err(|error| anyhow!(error))
+        } else {
+            anyhow::bail!(response.text().await?)
+        }
+    }
+}
+
+// Describes a failed token request. Providers that do not follow the RFC 6749 error format (i.e LinkedIn's
+// `serviceErrorCode`/`message` bodies) fail to parse, so their message is taken from the raw body instead.
+fn token_error<RE: std::error::Error + 'static, T: ErrorResponseType + std::fmt::Display>(
+    err: RequestTokenError<RE, StandardErrorResponse<T>>,
+) -> String {
+    match err {
+        RequestTokenError::ServerResponse(response) => match response.error_description() {
+            Some(description) => format!("{}: {}", response.error(), description),
+            None => response.error().to_string(),
+        },
+        RequestTokenError::Parse(_, body) => {
+            let text = String::from_utf8_lossy(&body).to_string();
+            match serde_json::from_slice::<serde_json::Value>(&body) {
+                Ok(json) => ["error_description", "message", "error"]
+                    .iter()
+                    .find_map(|key| json[key].as_str().map(ToString::to_string))
+                    .unwrap_or(text),
+                Err(_) => text,
+            }
+        }
+        err => err.to_string(),
+    }
+}
+
+// Token requests go through here so strategies can add headers (i.e Reddit's User-Agent) and provider quirks in the
+// token response can be smoothed out before oauth2 parses it. Twitch sends `scope` as a JSON array instead of the
+// RFC 6749 space delimited string and Shopify leaves `token_type` out.
+async fn token_http_client(
+    mut request: HttpRequest,
+    headers: HeaderMap,
+    dpop: Option<DpopKey>,
+    client: reqwest::Client,
+) -> Result<HttpResponse, oauth2::reqwest::Error<reqwest::Error>> {
+    request.headers.extend(headers);
+    let mut response = send_token_request(request, &client, dpop.as_ref()).await?;
+    if let Ok(mut json) = serde_json::from_slice::<serde_json::Value>(&response.body) {
+        if normalize_token_response(&mut json) {
+            response.body = serde_json::to_vec(&json).unwrap();
+        }
+    }
+    Ok(response)
+}
+
+// Returns whether the token response was changed.
+fn normalize_token_response(json: &mut serde_json::Value) -> bool {
+    let mut changed = false;
+    if let Some(scopes) = json["scope"].as_array() {
+        json["scope"] = serde_json::json!(scopes
+            .iter()
+            .filter_map(serde_json::Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" "));
+        changed = true;
+    }
+    if json["access_token"].is_string() && json["token_type"].is_null() {
+        json["token_type"] = serde_json::json!("bearer");
+        changed = true;
+    }
+    changed
+}
+
+// Client assertion for the token endpoint, see `ClientAuthMethod`.
+fn token_assertion(strategy: &dyn Strategy) -> anyhow::Result<Vec<(String, String)>> {
+    match strategy.token_url() {
+        Some(token_url) => client_assertion(strategy, token_url.as_str()),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Authenticates a request built here rather than by oauth2 (pushed authorization, device flow) the way the token
+// endpoint expects, `params` being its form fields and `endpoint` the audience of client assertions. Public clients
+// (i.e device apps) have no secret, they only send their id.
+fn authenticate_client(
+    strategy: &dyn Strategy,
+    mut request: reqwest::RequestBuilder,
+    params: &mut Vec<(String, String)>,
+    endpoint: &str,
+) -> anyhow::Result<reqwest::RequestBuilder> {
+    let secret = strategy.client_secret();
+    match strategy.client_auth_method() {
+        ClientAuthMethod::ClientSecretBasic | ClientAuthMethod::ClientSecretPost
+            if secret.is_empty() => {}
+        ClientAuthMethod::ClientSecretBasic => {
+            request = request.basic_auth(strategy.client_id(), Some(secret))
+        }
+        ClientAuthMethod::ClientSecretPost => params.push((String::from("client_secret"), secret)),
+        _ => params.extend(client_assertion(strategy, endpoint)?),
+    }
+    Ok(request)
+}
+
+// Client for the token, refresh, revocation, pushed authorization and device endpoints. Like oauth2's own client it does not
+// follow redirects, and it presents the strategy's TLS client certificate.
+fn token_client(strategy: &dyn Strategy) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    if let Some(certificate) = strategy.client_certificate() {
+        builder = certificate.configure(builder);
+    }
+    Ok(builder.build()?)
+}
+
+// Sends a request built by oauth2, through `DpopKey::send_with` when tokens are DPoP bound so the proof is added and
+// `DPoP-Nonce` challenges are answered.
+async fn send_token_request(
+    request: HttpRequest,
+    client: &reqwest::Client,
+    dpop: Option<&DpopKey>,
+) -> Result<HttpResponse, oauth2::reqwest::Error<reqwest::Error>> {
+    let request = client
+        .request(request.method, request.url)
+        .headers(request.headers)
+        .body(request.body)
+        .build()
+        .map_err(oauth2::reqwest::Error::Reqwest)?;
+    let response = match dpop {
+        Some(dpop) => dpop
+            .send_with(client, request, None)
+            .await
+            .map_err(|err| oauth2::reqwest::Error::Other(err.to_string()))?,
+        None => client
+            .execute(request)
+            .await
+            .map_err(oauth2::reqwest::Error::Reqwest)?,
+    };
+    Ok(HttpResponse {
+        status_code: response.status(),
+        headers: response.headers().clone(),
+        body: response
+            .bytes()
+            .await
+            .map_err(oauth2::reqwest::Error::Reqwest)?
+            .to_vec(),
+    })
+}
+
+// RFC 8705, a JWT access token bound to a certificate other than the one the client presented is refused. Providers
+// that do not bind their tokens leave `cnf` out, and opaque tokens can only be checked through introspection, both are
+// left alone.
+fn check_certificate_binding(
+    strategy: &dyn Strategy,
+    token: &PassportTokenResponse,
+) -> anyhow::Result<()> {
+    let certificate = match strategy.client_certificate() {
+        Some(certificate) => certificate,
+        None => return Ok(()),
+    };
+    let claims = token
+        .access_token()
+        .secret()
+        .split('.')
+        .nth(1)
+        .and_then(|claims| URL_SAFE_NO_PAD.decode(claims).ok())
+        .and_then(|claims| serde_json::from_slice::<serde_json::Value>(&claims).ok());
+    match claims
+        .as_ref()
+        .and_then(|claims| claims["cnf"]["x5t#S256"].as_str())
+    {
+        Some(thumbprint) if thumbprint != certificate.thumbprint() => {
+            anyhow::bail!("Access token is bound to another certificate")
+        }
+        _ => Ok(()),
+    }
+}
+
+fn dpop_bound(token: &PassportTokenResponse) -> bool {
+    matches!(token.token_type(), BasicTokenType::Extension(typ) if typ.eq_ignore_ascii_case("DPoP"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_auth::ClientCertificate;
+    use crate::strategies::{KeycloakStrategy, LinkedInStrategy};
+    use crate::test_server::{Response, TestServer};
+    use base64::engine::general_purpose::STANDARD;
+    use std::collections::VecDeque;
+
+    fn strategy() -> KeycloakStrategy {
+        KeycloakStrategy::new(
+            "https://sso.example.com",
+            "demo",
+            "id",
+            "secret",
+            vec!["openid"],
+            "https://example.com/callback",
+            "https://example.com/login",
+        )
+    }
+
+    fn token(access_token: &str) -> PassportTokenResponse {
+        serde_json::from_value(serde_json::json!({
+            "access_token": access_token,
+            "token_type": "Bearer",
+        }))
+        .unwrap()
+    }
+
+    fn jwt(claims: serde_json::Value) -> String {
+        format!(
+            "eyJhbGciOiJSUzI1NiJ9.{}.c2lnbmF0dXJl",
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    #[test]
+    fn certificate_binding() {
+        let certificate = ClientCertificate::from_pem(
+            include_str!("../tests/fixtures/client.crt"),
+            include_str!("../tests/fixtures/client.key"),
+        )
+        .unwrap();
+        let bound = token(&jwt(serde_json::json!({
+            "sub": "user",
+            "cnf": { "x5t#S256": certificate.thumbprint() },
+        })));
+        let other = token(&jwt(serde_json::json!({
+            "sub": "user",
+            "cnf": { "x5t#S256": "bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2" },
+        })));
+        let unbound = token(&jwt(serde_json::json!({ "sub": "user" })));
+        let opaque = token("2YotnFZFEjr1zCsicMWpAA");
+
+        let strategy_with_certificate = strategy().client_certificate(certificate);
+        assert!(check_certificate_binding(&strategy_with_certificate, &bound).is_ok());
+        assert!(check_certificate_binding(&strategy_with_certificate, &other).is_err());
+        assert!(check_certificate_binding(&strategy_with_certificate, &unbound).is_ok());
+        assert!(check_certificate_binding(&strategy_with_certificate, &opaque).is_ok());
+        // Without a certificate there is nothing to check.
+        assert!(check_certificate_binding(&strategy(), &unbound).is_ok());
+    }
+
+    // Token endpoint behind TLS that requires a client certificate, like an RFC 8705 provider. It answers each connection
+    // with a token bound to the next of `bindings` and reports the thumbprint of the certificate the client presented.
+    fn mtls_token_endpoint(bindings: Vec<String>) -> (String, std::sync::mpsc::Receiver<String>) {
+        use openssl::hash::MessageDigest;
+        use openssl::pkey::PKey;
+        use openssl::ssl::{SslAcceptor, SslMethod, SslVerifyMode};
+        use openssl::x509::X509;
+        use std::io::{BufRead, BufReader, Read, Write};
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_certificate(
+                &X509::from_pem(include_bytes!("../tests/fixtures/server.crt")).unwrap(),
+            )
+            .unwrap();
+        acceptor
+            .set_private_key(
+                &PKey::private_key_from_pem(include_bytes!("../tests/fixtures/server.key"))
+                    .unwrap(),
+            )
+            .unwrap();
+        acceptor
+            .cert_store_mut()
+            .add_cert(X509::from_pem(include_bytes!("../tests/fixtures/client.crt")).unwrap())
+            .unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = format!(
+            "https://127.0.0.1:{}",
+            listener.local_addr().unwrap().port()
+        );
+        let (presented, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for binding in bindings {
+                let (stream, _) = listener.accept().unwrap();
+                let mut stream = match acceptor.accept(stream) {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let certificate = stream.ssl().peer_certificate().unwrap();
+                let digest = certificate.digest(MessageDigest::sha256()).unwrap();
+                presented.send(URL_SAFE_NO_PAD.encode(digest)).unwrap();
+                let mut reader = BufReader::new(&mut stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                reader.read_exact(&mut vec![0; length]).unwrap();
+                let body = serde_json::json!({
+                    "access_token": jwt(serde_json::json!({ "cnf": { "x5t#S256": binding } })),
+                    "token_type": "Bearer",
+                    "expires_in": 300,
+                })
+                .to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (server, received)
+    }
+
+    #[tokio::test]
+    async fn certificate_bound_token_request() {
+        let certificate = ClientCertificate::from_pem(
+            include_str!("../tests/fixtures/client.crt"),
+            include_str!("../tests/fixtures/client.key"),
+        )
+        .unwrap()
+        .root_certificate(include_str!("../tests/fixtures/server.crt"))
+        .unwrap();
+        let (server, presented) = mtls_token_endpoint(vec![
+            certificate.thumbprint().to_string(),
+            String::from("bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2"),
+        ]);
+        let mut passport = PassPortBasicClient::default();
+        passport.using(
+            "keycloak",
+            KeycloakStrategy::new(
+                &server,
+                "demo",
+                "id",
+                "secret",
+                vec!["openid"],
+                "https://example.com/callback",
+                "https://example.com/login",
+            )
+            .client_certificate(certificate.clone()),
+        );
+
+        let token = passport.client_credentials("keycloak", vec!["read"]).await;
+        assert!(token.is_ok());
+        assert_eq!(presented.recv().unwrap(), certificate.thumbprint());
+        // Another scope skips the cached token, this time the provider bound it to some other certificate.
+        let token = passport.client_credentials("keycloak", vec!["write"]).await;
+        assert_eq!(
+            token.unwrap_err().to_string(),
+            "Access token is bound to another certificate"
+        );
+        assert_eq!(presented.recv().unwrap(), certificate.thumbprint());
+    }
+
+    const TOKEN_PATH: &str = "/realms/demo/protocol/openid-connect/token";
+
+    // Keycloak realm served by a test server.
+    fn keycloak(server: &TestServer, client_secret: &str) -> KeycloakStrategy {
+        KeycloakStrategy::new(
+            &server.origin,
+            "demo",
+            "id",
+            client_secret,
+            vec!["openid", "profile"],
+            "https://example.com/callback",
+            "https://example.com/login",
+        )
+    }
+
+    // Device endpoint, and a token endpoint answering polls with `responses` in turn while recording when they came.
+    async fn device_server(
+        responses: Vec<serde_json::Value>,
+    ) -> (TestServer, Arc<Mutex<Vec<tokio::time::Instant>>>) {
+        let responses = Mutex::new(VecDeque::from(responses));
+        let polls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&polls);
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/realms/demo/protocol/openid-connect/auth/device" => Response::json(
+                200,
+                serde_json::json!({
+                    "device_code": "device",
+                    "user_code": "WDJB-MJHT",
+                    "verification_uri": "https://sso.example.com/device",
+                    "expires_in": 600,
+                    "interval": 5,
+                }),
+            ),
+            TOKEN_PATH => {
+                recorded.lock().unwrap().push(tokio::time::Instant::now());
+                let response = responses.lock().unwrap().pop_front().unwrap();
+                match response["error"].is_string() {
+                    true => Response::json(400, response),
+                    false => Response::json(200, response),
+                }
+            }
+            _ => Response::json(200, serde_json::json!({ "sub": "user", "name": "Jane" })),
+        })
+        .await;
+        (server, polls)
+    }
+
+    fn device(expires_in: u64) -> DeviceAuthorization {
+        DeviceAuthorization {
+            provider: String::from("keycloak"),
+            device_code: String::from("device"),
+            user_code: String::from("WDJB-MJHT"),
+            verification_uri: String::from("https://sso.example.com/device"),
+            verification_uri_complete: None,
+            expires_in,
+            interval: 5,
+        }
+    }
+
+    // Seconds between the start of polling and each poll.
+    fn poll_times(
+        start: tokio::time::Instant,
+        polls: &Arc<Mutex<Vec<tokio::time::Instant>>>,
+    ) -> Vec<u64> {
+        polls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|poll| (*poll - start).as_secs())
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_flow_sign_in() {
+        let (server, polls) = device_server(vec![
+            serde_json::json!({ "error": "authorization_pending" }),
+            serde_json::json!({ "error": "slow_down", "interval": 12 }),
+            serde_json::json!({ "error": "authorization_pending" }),
+            serde_json::json!({ "access_token": "access", "token_type": "Bearer" }),
+        ])
+        .await;
+        let mut passport = PassPortBasicClient::default();
+        passport.using("keycloak", keycloak(&server, "secret"));
+
+        let device = passport.start_device_flow("keycloak").await.unwrap();
+        assert_eq!(device.user_code, "WDJB-MJHT");
+        assert_eq!(device.provider, "keycloak");
+        let basic = format!("Basic {}", STANDARD.encode("id:secret"));
+        let start = &server.requests()[0];
+        assert_eq!(start.form()["client_id"], "id");
+        assert_eq!(start.form()["scope"], "openid profile");
+        assert_eq!(start.header("authorization"), Some(basic.as_str()));
+
+        let begin = tokio::time::Instant::now();
+        let profile = match passport.poll_device_flow(device).await.unwrap() {
+            PassportResponse::Profile(profile) => profile,
+            PassportResponse::FailureRedirect(url) => panic!("Unexpected redirect to {}", url),
+        };
+        assert_eq!(profile["sub"], "user");
+        assert_eq!(profile["access_token"], "access");
+        // Every 5 seconds, then at the 12 seconds the provider asked for after `slow_down`.
+        assert_eq!(poll_times(begin, &polls), vec![5, 10, 22, 34]);
+        let poll = &server.requests()[1];
+        assert_eq!(
+            poll.form()["grant_type"],
+            "urn:ietf:params:oauth:grant-type:device_code"
+        );
+        assert_eq!(poll.form()["device_code"], "device");
+        assert_eq!(poll.header("authorization"), Some(basic.as_str()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_flow_endings() {
+        let outcome = |responses: Vec<serde_json::Value>, expires_in: u64| async move {
+            let (server, polls) = device_server(responses).await;
+            let mut passport = PassPortBasicClient::default();
+            passport.using("keycloak", keycloak(&server, "secret"));
+            let begin = tokio::time::Instant::now();
+            let outcome = passport.poll_device_flow(device(expires_in)).await;
+            (outcome, poll_times(begin, &polls))
+        };
+
+        // Without an interval in the answer, `slow_down` adds 5 seconds.
+        let (refused, polls) = outcome(
+            vec![
+                serde_json::json!({ "error": "slow_down" }),
+                serde_json::json!({ "error": "access_denied" }),
+            ],
+            600,
+        )
+        .await;
+        assert!(
+            matches!(refused.unwrap(), PassportResponse::FailureRedirect(url) if url.as_str() == "https://example.com/login")
+        );
+        assert_eq!(polls, vec![5, 15]);
+
+        let (expired, polls) =
+            outcome(vec![serde_json::json!({ "error": "expired_token" })], 600).await;
+        assert_eq!(
+            expired.err().unwrap().to_string(),
+            "The device code expired before the user signed in"
+        );
+        assert_eq!(polls, vec![5]);
+
+        // The code runs out while the user is still away, polling stops without asking again.
+        let (expired, polls) = outcome(
+            vec![
+                serde_json::json!({ "error": "authorization_pending" }),
+                serde_json::json!({ "error": "authorization_pending" }),
+            ],
+            12,
+        )
+        .await;
+        assert_eq!(
+            expired.err().unwrap().to_string(),
+            "The device code expired before the user signed in"
+        );
+        assert_eq!(polls, vec![5, 10]);
+
+        let (unknown, _) = outcome(
+            vec![serde_json::json!({ "error": "bad_verification_code" })],
+            600,
+        )
+        .await;
+        assert_eq!(
+            unknown.err().unwrap().to_string(),
+            "The provider does not recognize the device code"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn device_flow_public_client() {
+        let certificate = ClientCertificate::from_pem(
+            include_str!("../tests/fixtures/client.crt"),
+            include_str!("../tests/fixtures/client.key"),
+        )
+        .unwrap();
+        let (server, _) = device_server(vec![serde_json::json!({
+            "access_token": jwt(serde_json::json!({
+                "cnf": { "x5t#S256": "bwcK0esc3ACC3DB2Y5_lESsXE8o9ltc05O89jdN-dg2" },
+            })),
+            "token_type": "Bearer",
+        })])
+        .await;
+        let mut passport = PassPortBasicClient::default();
+        passport.using(
+            "keycloak",
+            keycloak(&server, "").client_certificate(certificate),
+        );
+        let device = passport.start_device_flow("keycloak").await.unwrap();
+        let error = passport.poll_device_flow(device).await.err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Access token is bound to another certificate"
+        );
+        // Without a secret only the client id is sent.
+        for request in server.requests() {
+            assert_eq!(request.header("authorization"), None);
+            assert_eq!(request.form()["client_id"], "id");
+            assert!(!request.form().contains_key("client_secret"));
+        }
+    }
+
+    // Token endpoint handing out client credentials tokens valid for `expires_in`, numbered in the order they were
+    // issued. Each answer takes a second, so concurrent callers overlap.
+    async fn client_credentials_server(expires_in: Option<u64>) -> TestServer {
+        let issued = std::sync::atomic::AtomicUsize::new(0);
+        TestServer::start(move |request| {
+            let count = issued.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let mut token = serde_json::json!({
+                "access_token": format!("token-{}", count),
+                "token_type": "Bearer",
+                "scope": request.form()["scope"],
+            });
+            if let Some(expires_in) = expires_in {
+                token["expires_in"] = serde_json::json!(expires_in);
+            }
+            Response::json(200, token).delay(Duration::from_secs(1))
+        })
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_credentials_cache() {
+        let server = client_credentials_server(Some(300)).await;
+        let mut passport = PassPortBasicClient::default();
+        passport.using("keycloak", keycloak(&server, "secret"));
+        passport.using("other", keycloak(&server, "secret"));
+        let token = |provider: &'static str, scopes: Vec<&'static str>| {
+            let passport = passport.clone();
+            async move {
+                passport
+                    .client_credentials(provider, scopes)
+                    .await
+                    .unwrap()
+                    .access_token()
+                    .secret()
+                    .clone()
+            }
+        };
+
+        assert_eq!(token("keycloak", vec!["read", "write"]).await, "token-0");
+        let request = &server.requests()[0];
+        assert_eq!(request.form()["grant_type"], "client_credentials");
+        assert_eq!(request.form()["scope"], "read write");
+        // The same scopes in another order, or repeated, are the same token.
+        assert_eq!(
+            token("keycloak", vec!["write", "read", "read"]).await,
+            "token-0"
+        );
+        assert_eq!(token("keycloak", vec!["read"]).await, "token-1");
+        assert_eq!(token("other", vec!["read", "write"]).await, "token-2");
+        assert_eq!(server.count(TOKEN_PATH), 3);
+
+        // Renewed once less than a minute is left.
+        tokio::time::advance(Duration::from_secs(230)).await;
+        assert_eq!(token("keycloak", vec!["read", "write"]).await, "token-0");
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(token("keycloak", vec!["read", "write"]).await, "token-3");
+        assert_eq!(token("keycloak", vec!["read", "write"]).await, "token-3");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_credentials_concurrent_requests() {
+        let server = client_credentials_server(Some(300)).await;
+        let mut passport = PassPortBasicClient::default();
+        passport.using("keycloak", keycloak(&server, "secret"));
+        let (a, b, c, d) = tokio::join!(
+            passport.client_credentials("keycloak", vec!["read"]),
+            passport.client_credentials("keycloak", vec!["read"]),
+            passport.client_credentials("keycloak", vec!["read"]),
+            passport.client_credentials("keycloak", vec!["write"]),
+        );
+        // The callers asking for `read` waited for the first request instead of sending their own.
+        let read = [a, b, c].map(|token| token.unwrap().access_token().secret().clone());
+        assert_eq!(read[0], read[1]);
+        assert_eq!(read[0], read[2]);
+        assert_ne!(d.unwrap().access_token().secret(), &read[0]);
+        assert_eq!(server.count(TOKEN_PATH), 2);
+
+        // Tokens without an expiry are fetched every time.
+        let server = client_credentials_server(None).await;
+        let mut passport = PassPortBasicClient::default();
+        passport.using("keycloak", keycloak(&server, "secret"));
+        passport
+            .client_credentials("keycloak", vec!["read"])
+            .await
+            .unwrap();
+        passport
+            .client_credentials("keycloak", vec!["read"])
+            .await
+            .unwrap();
+        assert_eq!(server.count(TOKEN_PATH), 2);
+    }
+
+    const PAR_PATH: &str = "/realms/demo/protocol/openid-connect/ext/par/request";
+
+    // Keycloak realm taking pushed authorization requests, each one valid 60 seconds longer than the one before.
+    async fn par_server() -> TestServer {
+        let pushed = std::sync::atomic::AtomicUsize::new(0);
+        TestServer::start(move |request| match request.path.as_str() {
+            PAR_PATH if request.form().contains_key("client_secret") => {
+                Response::json(401, serde_json::json!({ "error": "invalid_client" }))
+            }
+            PAR_PATH => {
+                let count = pushed.fetch_add(1, std::sync::atomic::Ordering::SeqCst) as i64;
+                Response::json(
+                    201,
+                    serde_json::json!({
+                        "request_uri": format!("urn:ietf:params:oauth:request_uri:{}", count),
+                        "expires_in": 60 * (count + 1),
+                    }),
+                )
+            }
+            TOKEN_PATH => Response::json(
+                200,
+                serde_json::json!({ "access_token": "access", "token_type": "Bearer" }),
+            ),
+            _ => Response::json(200, serde_json::json!({ "sub": "user" })),
+        })
+        .await
+    }
+
+    fn callback(state: &str) -> StateCode {
+        StateCode {
+            state: Some(CsrfToken::new(state.to_string())),
+            code: Some(String::from("code")),
+            params: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn pushed_authorization_request() {
+        let server = par_server().await;
+        let mut passport = PassPortBasicClient::default();
+        passport.using(
+            "keycloak",
+            keycloak(&server, "secret").pushed_authorization_requests(),
+        );
+        passport.authenticate("keycloak");
+
+        let url = passport.generate_redirect_url_async(None).await.unwrap();
+        let parsed = url.parse::<Url>().unwrap();
+        assert_eq!(parsed.path(), "/realms/demo/protocol/openid-connect/auth");
+        // Only the client and the reference to the pushed parameters go through the browser.
+        assert_eq!(
+            parsed.query_pairs().into_owned().collect::<Vec<_>>(),
+            vec![
+                (String::from("client_id"), String::from("id")),
+                (
+                    String::from("request_uri"),
+                    String::from("urn:ietf:params:oauth:request_uri:0")
+                ),
+            ]
+        );
+        let pushed = server.requests()[0].clone();
+        let params = pushed.form();
+        assert_eq!(params["response_type"], "code");
+        assert_eq!(params["client_id"], "id");
+        assert_eq!(params["redirect_uri"], "https://example.com/callback");
+        assert_eq!(params["scope"], "openid profile");
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert!(!params.contains_key("client_secret"));
+        assert_eq!(
+            pushed.header("authorization"),
+            Some(format!("Basic {}", STANDARD.encode("id:secret")).as_str())
+        );
+
+        // Each login keeps the expiry of its own pushed request.
+        let other = passport.generate_redirect_url_async(None).await.unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let expires_at = passport.redirect_url_expires_at(&url).unwrap();
+        assert!((now + 55..=now + 60).contains(&expires_at));
+        let expires_at = passport.redirect_url_expires_at(&other).unwrap();
+        assert!((now + 115..=now + 120).contains(&expires_at));
+        assert_eq!(
+            passport.redirect_url_expires_at("https://example.com/?request_uri=urn:other"),
+            None
+        );
+
+        let response = passport
+            .get_profile(callback(&params["state"]))
+            .await
+            .unwrap();
+        assert!(matches!(response, PassportResponse::Profile(profile) if profile["sub"] == "user"));
+        let exchange = server
+            .requests()
+            .into_iter()
+            .find(|request| request.path == TOKEN_PATH)
+            .unwrap();
+        assert_eq!(exchange.form()["code"], "code");
+        assert!(exchange.form().contains_key("code_verifier"));
+        assert_eq!(passport.redirect_url_expires_at(&url), None);
+    }
+
+    #[tokio::test]
+    async fn expired_pushed_authorization_request() {
+        let server = par_server().await;
+        let mut passport = PassPortBasicClient::default();
+        passport.using(
+            "keycloak",
+            keycloak(&server, "secret").pushed_authorization_requests(),
+        );
+        passport.authenticate("keycloak");
+        passport.generate_redirect_url_async(None).await.unwrap();
+        let state = server.requests()[0].form()["state"].clone();
+        passport.pushed.get_mut(&state).unwrap().1 = chrono::Utc::now().timestamp() - 1;
+
+        let response = passport.get_profile(callback(&state)).await.unwrap();
+        assert!(
+            matches!(response, PassportResponse::FailureRedirect(url) if url.as_str() == "https://example.com/login")
+        );
+        assert_eq!(server.count(TOKEN_PATH), 0);
+        // The login is over, its state cannot be used again.
+        assert!(passport.sessions.is_empty());
+
+        // A rejected push leaves nothing behind.
+        let mut passport = PassPortBasicClient::default();
+        passport.using(
+            "keycloak",
+            keycloak(&server, "secret")
+                .pushed_authorization_requests()
+                .client_auth_method(ClientAuthMethod::ClientSecretPost),
+        );
+        passport.authenticate("keycloak");
+        let error = passport
+            .generate_redirect_url_async(None)
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("invalid_client"));
+        assert!(passport.sessions.is_empty());
+        assert!(passport.pushed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn non_standard_token_errors() {
+        let server = TestServer::start(|request| match request.form()["code"].as_str() {
+            "revoked" => Response::json(
+                401,
+                serde_json::json!({
+                    "serviceErrorCode": 65604,
+                    "message": "Invalid authorization code",
+                    "status": 401,
+                }),
+            ),
+            "text" => Response::text(500, "Internal Server Error"),
+            _ => Response::json(
+                400,
+                serde_json::json!({
+                    "error": "invalid_grant",
+                    "error_description": "Authorization code expired",
+                }),
+            ),
+        })
+        .await;
+        let mut strategy = LinkedInStrategy::new(
+            "id",
+            "secret",
+            vec![],
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        strategy.token_uri = server.url("/oauth/v2/accessToken");
+        let mut passport = PassPortBasicClient::default();
+        passport.using("linkedin", strategy);
+        passport.authenticate("linkedin");
+
+        for (code, message) in [
+            // LinkedIn's own error format.
+            ("revoked", "Invalid authorization code"),
+            ("text", "Internal Server Error"),
+            ("expired", "invalid_grant: Authorization code expired"),
+        ] {
+            let url = passport.generate_redirect_url().parse::<Url>().unwrap();
+            let state = url.query_pairs().into_owned().collect::<HashMap<_, _>>()["state"].clone();
+            let error = passport
+                .get_profile(StateCode {
+                    code: Some(code.to_string()),
+                    ..callback(&state)
+                })
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(error.to_string(), message);
+            assert!(passport.sessions.is_empty());
+        }
+        // Credentials go in the body and LinkedIn takes no PKCE verifier from confidential clients.
+        let exchange = server.requests()[0].form();
+        assert_eq!(exchange["client_id"], "id");
+        assert_eq!(exchange["client_secret"], "secret");
+        assert!(!exchange.contains_key("code_verifier"));
+    }
 }
@@ -0,0 +1,360 @@
+use std::fmt::Debug;
+
+use anyhow::anyhow;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use p256::ecdsa::signature::{SignatureEncoding, Signer};
+use sha2::{Digest, Sha256};
+
+use crate::strategies::Strategy;
+
+/// How a strategy authenticates the client at the token, refresh and revocation endpoints
+/// (`token_endpoint_auth_method`).
+#[derive(Clone, Debug)]
+pub enum ClientAuthMethod {
+    /// Id and secret in the `Authorization: Basic` header.
+    ClientSecretBasic,
+    /// Id and secret in the request body.
+    ClientSecretPost,
+    /// Assertion signed with the client secret (HS256, RFC 7523).
+    ClientSecretJwt,
+    /// Assertion signed with the client's private key (RFC 7523).
+    PrivateKeyJwt(ClientKey),
+    /// The TLS client certificate of the strategy (RFC 8705), only the id is sent.
+    TlsClientAuth,
+    /// Public client, only the id is sent.
+    None,
+}
+
+impl ClientAuthMethod {
+    /// Registered name of the method, i.e `private_key_jwt`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ClientSecretBasic => "client_secret_basic",
+            Self::ClientSecretPost => "client_secret_post",
+            Self::ClientSecretJwt => "client_secret_jwt",
+            Self::PrivateKeyJwt(_) => "private_key_jwt",
+            Self::TlsClientAuth => "tls_client_auth",
+            Self::None => "none",
+        }
+    }
+
+    /// Whether the secret is sent as is, the other methods keep it out of the requests.
+    pub fn sends_secret(&self) -> bool {
+        matches!(self, Self::ClientSecretBasic | Self::ClientSecretPost)
+    }
+}
+
+/// Private key client assertions are signed with, RS256 for RSA keys and ES256 for P-256 keys. The `kid` tells the
+/// provider which of the registered public keys to verify with.
+#[derive(Clone)]
+pub struct ClientKey {
+    kid: String,
+    key: KeyKind,
+}
+
+#[derive(Clone)]
+enum KeyKind {
+    Rsa(Box<rsa::pkcs1v15::SigningKey<Sha256>>),
+    Ec(p256::ecdsa::SigningKey),
+}
+
+impl Debug for ClientKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientKey")
+            .field("kid", &self.kid)
+            .field("alg", &self.algorithm())
+            .finish()
+    }
+}
+
+impl ClientKey {
+    /// Reads a PKCS#8 (`PRIVATE KEY`), PKCS#1 (`RSA PRIVATE KEY`) or SEC1 (`EC PRIVATE KEY`) PEM key.
+    pub fn from_pem(kid: &str, pem: &str) -> anyhow::Result<Self> {
+        use p256::pkcs8::DecodePrivateKey;
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+
+        let key = if pem.contains("BEGIN RSA PRIVATE KEY") {
+            KeyKind::Rsa(Box::new(rsa::RsaPrivateKey::from_pkcs1_pem(pem)?.into()))
+        } else if pem.contains("BEGIN EC PRIVATE KEY") {
+            KeyKind::Ec(p256::SecretKey::from_sec1_pem(pem)?.into())
+        } else if let Ok(key) = rsa::RsaPrivateKey::from_pkcs8_pem(pem) {
+            KeyKind::Rsa(Box::new(key.into()))
+        } else {
+            KeyKind::Ec(
+                p256::SecretKey::from_pkcs8_pem(pem)
+                    .map_err(|_| anyhow!("Client keys must be RSA or P-256 private keys"))?
+                    .into(),
+            )
+        };
+        Ok(Self {
+            kid: kid.to_string(),
+            key,
+        })
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self.key {
+            KeyKind::Rsa(_) => "RS256",
+            KeyKind::Ec(_) => "ES256",
+        }
+    }
+
+    fn sign(&self, input: &[u8]) -> Vec<u8> {
+        match &self.key {
+            KeyKind::Rsa(key) => key.sign(input).to_vec(),
+            KeyKind::Ec(key) => {
+                let signature: p256::ecdsa::Signature = key.sign(input);
+                signature.to_vec()
+            }
+        }
+    }
+}
+
+/// TLS client certificate (RFC 8705) presented on the token, refresh and profile requests. Tokens issued while presenting
+/// it are bound to its `x5t#S256` thumbprint.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    identity: reqwest::Identity,
+    thumbprint: String,
+    roots: Vec<reqwest::Certificate>,
+}
+
+impl Debug for ClientCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCertificate")
+            .field("x5t#S256", &self.thumbprint)
+            .finish()
+    }
+}
+
+impl ClientCertificate {
+    /// `certificate` is the PEM certificate (chain, leaf first) and `key` its PKCS#8 PEM private key.
+    pub fn from_pem(certificate: &str, key: &str) -> anyhow::Result<Self> {
+        let identity = reqwest::Identity::from_pkcs8_pem(certificate.as_bytes(), key.as_bytes())?;
+        let der = certificate
+            .split("-----BEGIN CERTIFICATE-----")
+            .nth(1)
+            .and_then(|rest| rest.split("-----END CERTIFICATE-----").next())
+            .ok_or_else(|| anyhow!("No certificate in PEM"))?;
+        let der = STANDARD.decode(der.split_whitespace().collect::<String>())?;
+        Ok(Self {
+            identity,
+            thumbprint: URL_SAFE_NO_PAD.encode(Sha256::digest(der)),
+            roots: Vec::new(),
+        })
+    }
+
+    /// Trusts an extra CA for the provider's server certificate, i.e a private PKI or a local test server.
+    pub fn root_certificate(mut self, pem: &str) -> anyhow::Result<Self> {
+        self.roots
+            .push(reqwest::Certificate::from_pem(pem.as_bytes())?);
+        Ok(self)
+    }
+
+    /// `x5t#S256` of the certificate, the `cnf` claim of bound tokens carries it.
+    pub fn thumbprint(&self) -> &str {
+        &self.thumbprint
+    }
+
+    pub(crate) fn configure(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        self.roots
+            .iter()
+            .fold(builder.identity(self.identity.clone()), |builder, root| {
+                builder.add_root_certificate(root.clone())
+            })
+    }
+}
+
+// `client_assertion` parameters for the JWT methods, nothing for the others. The `audience` is the endpoint called.
+pub(crate) fn client_assertion(
+    strategy: &dyn Strategy,
+    audience: &str,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let (algorithm, key) = match strategy.client_auth_method() {
+        ClientAuthMethod::ClientSecretJwt => ("HS256", None),
+        ClientAuthMethod::PrivateKeyJwt(key) => (key.algorithm(), Some(key)),
+        _ => return Ok(Vec::new()),
+    };
+    let mut header = serde_json::json!({ "alg": algorithm, "typ": "JWT" });
+    if let Some(key) = key.as_ref() {
+        header["kid"] = serde_json::json!(key.kid);
+    }
+    let now = chrono::Utc::now().timestamp();
+    let claims = serde_json::json!({
+        "iss": strategy.client_id(),
+        "sub": strategy.client_id(),
+        "aud": audience,
+        "jti": URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()),
+        "iat": now,
+        "exp": now + 60,
+    });
+    let input = format!(
+        "{}.{}",
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+    );
+    let signature = match key {
+        Some(key) => key.sign(input.as_bytes()),
+        None => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(strategy.client_secret().as_bytes())?;
+            mac.update(input.as_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+    };
+    Ok(vec![
+        (
+            String::from("client_assertion_type"),
+            String::from("urn:ietf:params:oauth:client-assertion-type:jwt-bearer"),
+        ),
+        (
+            String::from("client_assertion"),
+            format!("{}.{}", input, URL_SAFE_NO_PAD.encode(signature)),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::KeycloakStrategy;
+
+    const CERTIFICATE: &str = include_str!("../tests/fixtures/client.crt");
+    const KEY: &str = include_str!("../tests/fixtures/client.key");
+    const RSA_KEY: &str = include_str!("../tests/fixtures/rsa.key");
+    const TOKEN_URL: &str = "https://sso.example.com/realms/demo/protocol/openid-connect/token";
+
+    fn strategy(method: ClientAuthMethod) -> KeycloakStrategy {
+        KeycloakStrategy::new(
+            "https://sso.example.com",
+            "demo",
+            "id",
+            "secret",
+            vec!["openid"],
+            "https://example.com/callback",
+            "https://example.com/login",
+        )
+        .client_auth_method(method)
+    }
+
+    // Header, claims, signing input and signature of the `client_assertion` sent for `method`.
+    fn assertion(
+        method: ClientAuthMethod,
+    ) -> (serde_json::Value, serde_json::Value, String, Vec<u8>) {
+        let params = client_assertion(&strategy(method), TOKEN_URL).unwrap();
+        assert_eq!(params[0].0, "client_assertion_type");
+        assert_eq!(
+            params[0].1,
+            "urn:ietf:params:oauth:client-assertion-type:jwt-bearer"
+        );
+        assert_eq!(params[1].0, "client_assertion");
+        let (input, signature) = params[1].1.rsplit_once('.').unwrap();
+        let (header, claims) = input.split_once('.').unwrap();
+        let decode = |part: &str| {
+            serde_json::from_slice::<serde_json::Value>(&URL_SAFE_NO_PAD.decode(part).unwrap())
+                .unwrap()
+        };
+        (
+            decode(header),
+            decode(claims),
+            input.to_string(),
+            URL_SAFE_NO_PAD.decode(signature).unwrap(),
+        )
+    }
+
+    // RFC 7523 claims, identifying the client to the endpoint it calls for a minute.
+    fn check_claims(claims: &serde_json::Value) {
+        assert_eq!(claims["iss"], "id");
+        assert_eq!(claims["sub"], "id");
+        assert_eq!(claims["aud"], TOKEN_URL);
+        assert_eq!(claims["jti"].as_str().unwrap().len(), 22);
+        let now = chrono::Utc::now().timestamp();
+        let issued = claims["iat"].as_i64().unwrap();
+        assert!((now - 5..=now).contains(&issued));
+        assert_eq!(claims["exp"].as_i64().unwrap(), issued + 60);
+    }
+
+    #[test]
+    fn rs256_assertion() {
+        use rsa::signature::{Keypair, Verifier};
+
+        let key = ClientKey::from_pem("rsa-1", RSA_KEY).unwrap();
+        let verifying_key = match &key.key {
+            KeyKind::Rsa(key) => key.verifying_key(),
+            KeyKind::Ec(_) => panic!("Expected an RSA key"),
+        };
+        let (header, claims, input, signature) = assertion(ClientAuthMethod::PrivateKeyJwt(key));
+        assert_eq!(
+            header,
+            serde_json::json!({ "alg": "RS256", "typ": "JWT", "kid": "rsa-1" })
+        );
+        check_claims(&claims);
+        let signature = rsa::pkcs1v15::Signature::try_from(signature.as_slice()).unwrap();
+        verifying_key.verify(input.as_bytes(), &signature).unwrap();
+        assert!(verifying_key
+            .verify(format!("{}x", input).as_bytes(), &signature)
+            .is_err());
+    }
+
+    #[test]
+    fn es256_assertion() {
+        use p256::ecdsa::signature::Verifier;
+
+        let key = ClientKey::from_pem("ec-1", KEY).unwrap();
+        let verifying_key = match &key.key {
+            KeyKind::Ec(key) => *key.verifying_key(),
+            KeyKind::Rsa(_) => panic!("Expected a P-256 key"),
+        };
+        let (header, claims, input, signature) = assertion(ClientAuthMethod::PrivateKeyJwt(key));
+        assert_eq!(
+            header,
+            serde_json::json!({ "alg": "ES256", "typ": "JWT", "kid": "ec-1" })
+        );
+        check_claims(&claims);
+        // JWS carries the raw r || s signature, not DER.
+        assert_eq!(signature.len(), 64);
+        let signature = p256::ecdsa::Signature::from_slice(&signature).unwrap();
+        verifying_key.verify(input.as_bytes(), &signature).unwrap();
+        // Each assertion is single use.
+        let (_, other, _, _) = assertion(ClientAuthMethod::PrivateKeyJwt(
+            ClientKey::from_pem("ec-1", KEY).unwrap(),
+        ));
+        assert_ne!(claims["jti"], other["jti"]);
+    }
+
+    #[test]
+    fn hs256_assertion() {
+        let (header, claims, input, signature) = assertion(ClientAuthMethod::ClientSecretJwt);
+        assert_eq!(header, serde_json::json!({ "alg": "HS256", "typ": "JWT" }));
+        check_claims(&claims);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"secret").unwrap();
+        mac.update(input.as_bytes());
+        mac.verify_slice(&signature).unwrap();
+
+        // The secret based methods send no assertion.
+        for method in [
+            ClientAuthMethod::ClientSecretBasic,
+            ClientAuthMethod::ClientSecretPost,
+            ClientAuthMethod::TlsClientAuth,
+            ClientAuthMethod::None,
+        ] {
+            assert!(client_assertion(&strategy(method), TOKEN_URL)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    #[test]
+    fn certificate_thumbprint() {
+        // openssl x509 -in client.crt -outform der | openssl dgst -sha256 -binary | base64url
+        let certificate = ClientCertificate::from_pem(CERTIFICATE, KEY).unwrap();
+        assert_eq!(
+            certificate.thumbprint(),
+            "NzuYAIyPUHPDgmuzG5eP0L2zssJNh1TSf4wmyWJFWjc"
+        );
+        let certificate = certificate.root_certificate(CERTIFICATE).unwrap();
+        assert_eq!(certificate.roots.len(), 1);
+        assert!(ClientCertificate::from_pem("not a certificate", KEY).is_err());
+    }
+}
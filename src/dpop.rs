@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::{Method, StatusCode, Url};
+use sha2::{Digest, Sha256};
+
+/// ES256 key pair DPoP proofs are signed with, along with the nonces servers handed out for it.
+/// Tokens bound to the key are useless without it, persist it with `to_jwk` next to them.
+#[derive(Clone)]
+pub struct DpopKey {
+    key: SigningKey,
+    // Latest `DPoP-Nonce` per origin, authorization and resource servers hand out their own.
+    nonces: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Debug for DpopKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DpopKey")
+            .field("thumbprint", &self.thumbprint())
+            .finish()
+    }
+}
+
+impl DpopKey {
+    pub fn generate() -> Self {
+        Self::from_signing_key(SigningKey::random(&mut rand::rngs::OsRng))
+    }
+
+    fn from_signing_key(key: SigningKey) -> Self {
+        Self {
+            key,
+            nonces: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Restores a key saved with `to_jwk`.
+    pub fn from_jwk(jwk: &serde_json::Value) -> anyhow::Result<Self> {
+        if jwk["kty"] != "EC" || jwk["crv"] != "P-256" {
+            anyhow::bail!("DPoP keys must be P-256 EC keys")
+        }
+        let d = jwk["d"]
+            .as_str()
+            .ok_or_else(|| anyhow!("DPoP key without private part"))?;
+        let key = SigningKey::from_slice(&URL_SAFE_NO_PAD.decode(d)?)?;
+        Ok(Self::from_signing_key(key))
+    }
+
+    /// Private JWK of the key.
+    pub fn to_jwk(&self) -> serde_json::Value {
+        let mut jwk = self.public_jwk();
+        jwk["d"] = serde_json::json!(URL_SAFE_NO_PAD.encode(self.key.to_bytes()));
+        jwk
+    }
+
+    /// Public JWK of the key, as sent in the proof header.
+    pub fn public_jwk(&self) -> serde_json::Value {
+        let (x, y) = self.coordinates();
+        serde_json::json!({ "kty": "EC", "crv": "P-256", "x": x, "y": y })
+    }
+
+    /// RFC 7638 thumbprint of the key, the `jkt` tokens are bound to.
+    pub fn thumbprint(&self) -> String {
+        // Our own public JWK always has the required members.
+        jwk_thumbprint(&self.public_jwk()).unwrap()
+    }
+
+    fn coordinates(&self) -> (String, String) {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        (
+            URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+            URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+        )
+    }
+
+    /// Signs a proof for a request. The `access_token` is hashed into `ath` when the request carries one, and the last
+    /// nonce the origin sent is included.
+    pub fn proof(
+        &self,
+        method: &Method,
+        url: &Url,
+        access_token: Option<&str>,
+    ) -> anyhow::Result<String> {
+        let mut htu = url.clone();
+        htu.set_query(None);
+        htu.set_fragment(None);
+        let header = serde_json::json!({
+            "typ": "dpop+jwt",
+            "alg": "ES256",
+            "jwk": self.public_jwk(),
+        });
+        let mut claims = serde_json::json!({
+            "jti": URL_SAFE_NO_PAD.encode(rand::random::<[u8; 16]>()),
+            "htm": method.as_str(),
+            "htu": htu.as_str(),
+            "iat": chrono::Utc::now().timestamp(),
+        });
+        if let Some(nonce) = self.nonces.lock().unwrap().get(&Self::origin(url)) {
+            claims["nonce"] = serde_json::json!(nonce);
+        }
+        if let Some(token) = access_token {
+            claims["ath"] = serde_json::json!(URL_SAFE_NO_PAD.encode(Sha256::digest(token)));
+        }
+        let input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature: Signature = self.key.sign(input.as_bytes());
+        Ok(format!(
+            "{}.{}",
+            input,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+
+    /// Sends the request with a proof, and `Authorization: DPoP <token>` when an `access_token` is given.
+    /// A server asking for a fresh nonce (`use_dpop_nonce`) gets the request once more with it.
+    pub async fn send(
+        &self,
+        request: reqwest::Request,
+        access_token: Option<&str>,
+    ) -> anyhow::Result<reqwest::Response> {
+        self.send_with(&reqwest::Client::new(), request, access_token)
+            .await
+    }
+
+    /// `send` through a configured client, i.e one presenting a TLS client certificate.
+    pub async fn send_with(
+        &self,
+        client: &reqwest::Client,
+        request: reqwest::Request,
+        access_token: Option<&str>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut retried = false;
+        loop {
+            let mut attempt = request
+                .try_clone()
+                .ok_or_else(|| anyhow!("DPoP requests must have a cloneable body"))?;
+            let proof = self.proof(attempt.method(), attempt.url(), access_token)?;
+            attempt
+                .headers_mut()
+                .insert("DPoP", HeaderValue::from_str(&proof)?);
+            if let Some(token) = access_token {
+                attempt.headers_mut().insert(
+                    AUTHORIZATION,
+                    HeaderValue::from_str(&format!("DPoP {}", token))?,
+                );
+            }
+            let response = client.execute(attempt).await?;
+            let fresh_nonce = match response
+                .headers()
+                .get("DPoP-Nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+            {
+                Some(nonce) => {
+                    let origin = Self::origin(request.url());
+                    let mut nonces = self.nonces.lock().unwrap();
+                    nonces.insert(origin, nonce.to_string()).as_deref() != Some(nonce)
+                }
+                None => false,
+            };
+            // Servers answer a missing or stale nonce with 400 (authorization server) or 401 (resource server)
+            // along with the nonce to use.
+            let rejected = matches!(
+                response.status(),
+                StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED
+            );
+            if rejected && fresh_nonce && !retried {
+                retried = true;
+                continue;
+            }
+            return Ok(response);
+        }
+    }
+
+    fn origin(url: &Url) -> String {
+        url.origin().ascii_serialization()
+    }
+}
+
+// RFC 7638 thumbprint of a public JWK, the SHA-256 of its required members in lexicographic order without whitespace.
+pub(crate) fn jwk_thumbprint(jwk: &serde_json::Value) -> anyhow::Result<String> {
+    let members: &[&str] = match jwk["kty"].as_str() {
+        Some("EC") => &["crv", "kty", "x", "y"],
+        Some("RSA") => &["e", "kty", "n"],
+        Some("oct") => &["k", "kty"],
+        _ => anyhow::bail!("Unsupported JWK key type"),
+    };
+    let mut canonical = Vec::new();
+    for member in members {
+        let value = jwk[member]
+            .as_str()
+            .ok_or_else(|| anyhow!("JWK without {}", member))?;
+        canonical.push(format!("\"{}\":{}", member, serde_json::json!(value)));
+    }
+    let canonical = format!("{{{}}}", canonical.join(","));
+    Ok(URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use p256::ecdsa::signature::Verifier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn decode(part: &str) -> serde_json::Value {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn thumbprints() {
+        // RFC 7638, section 3.1.
+        let rsa = serde_json::json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29",
+        });
+        assert_eq!(
+            jwk_thumbprint(&rsa).unwrap(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+        // RFC 9449, the key of the example proofs and the `jkt` of the tokens bound to it.
+        let ec = serde_json::json!({
+            "kty": "EC",
+            "x": "l8tFrhx-34tV3hRICRDY9zCkDlpBhF42UQUfWVAWBFs",
+            "y": "9VE4jf_Ok_o64zbTTlcuNJajHmt6v9TDVrU0CdvGRDA",
+            "crv": "P-256",
+        });
+        assert_eq!(
+            jwk_thumbprint(&ec).unwrap(),
+            "0ZcOCORZNYy-DWpqq30jZyJGHTN0d2HglBV3uiguA4I"
+        );
+        assert!(jwk_thumbprint(&serde_json::json!({ "kty": "EC", "crv": "P-256" })).is_err());
+
+        let key = DpopKey::generate();
+        assert_eq!(key.thumbprint(), jwk_thumbprint(&key.public_jwk()).unwrap());
+        let restored = DpopKey::from_jwk(&key.to_jwk()).unwrap();
+        assert_eq!(restored.thumbprint(), key.thumbprint());
+        assert!(DpopKey::from_jwk(&key.public_jwk()).is_err());
+    }
+
+    #[test]
+    fn proof_claims() {
+        let key = DpopKey::generate();
+        let url = "https://resource.example.org/protectedresource?id=1#top"
+            .parse::<Url>()
+            .unwrap();
+        key.nonces.lock().unwrap().insert(
+            String::from("https://resource.example.org"),
+            String::from("eyJ7S_zG.eyJH0-Z.HX4w-7v"),
+        );
+        let proof = key
+            .proof(
+                &Method::GET,
+                &url,
+                Some("Kz~8mXK1EalYznwH-LC-1fBAo.4Ljp~zsPE_NeO.gxU"),
+            )
+            .unwrap();
+        let parts = proof.split('.').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 3);
+        let header = decode(parts[0]);
+        assert_eq!(header["typ"], "dpop+jwt");
+        assert_eq!(header["alg"], "ES256");
+        assert_eq!(header["jwk"], key.public_jwk());
+        assert!(header["jwk"].get("d").is_none());
+        let claims = decode(parts[1]);
+        assert_eq!(claims["htm"], "GET");
+        assert_eq!(
+            claims["htu"],
+            "https://resource.example.org/protectedresource"
+        );
+        assert_eq!(claims["nonce"], "eyJ7S_zG.eyJH0-Z.HX4w-7v");
+        // RFC 9449, section 7.1.
+        assert_eq!(claims["ath"], "fUHyO2r2Z3DZ53EsNrWBb0xWXoaNy59IiKCAqksmQEo");
+        assert!((chrono::Utc::now().timestamp() - claims["iat"].as_i64().unwrap()).abs() < 5);
+        assert!(!claims["jti"].as_str().unwrap().is_empty());
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(parts[2]).unwrap()).unwrap();
+        key.key
+            .verifying_key()
+            .verify(format!("{}.{}", parts[0], parts[1]).as_bytes(), &signature)
+            .unwrap();
+
+        // Token requests carry no access token, and nonces belong to the origin that sent them.
+        let proof = key
+            .proof(
+                &Method::POST,
+                &"https://server.example.com/token".parse().unwrap(),
+                None,
+            )
+            .unwrap();
+        let claims = decode(proof.split('.').nth(1).unwrap());
+        assert_eq!(claims["htm"], "POST");
+        assert!(claims.get("ath").is_none());
+        assert!(claims.get("nonce").is_none());
+    }
+
+    // Token endpoint asking for a nonce, a new one each time unless `stable`.
+    async fn nonce_server(stable: bool) -> TestServer {
+        let issued = AtomicUsize::new(0);
+        TestServer::start(move |request| {
+            let nonce = request
+                .header("dpop")
+                .and_then(|proof| proof.split('.').nth(1))
+                .map(decode)
+                .map(|claims| claims["nonce"].clone())
+                .unwrap_or_default();
+            if stable && nonce == "nonce-0" {
+                return Response::json(
+                    200,
+                    serde_json::json!({ "access_token": "access", "token_type": "DPoP" }),
+                );
+            }
+            let count = issued.fetch_add(1, Ordering::SeqCst);
+            let fresh = format!("nonce-{}", if stable { 0 } else { count });
+            Response::json(400, serde_json::json!({ "error": "use_dpop_nonce" }))
+                .header("DPoP-Nonce", &fresh)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn nonce_retry() {
+        let key = DpopKey::generate();
+        let server = nonce_server(true).await;
+        let client = reqwest::Client::new();
+        let request = client
+            .post(server.url("/token"))
+            .body("grant_type=authorization_code")
+            .build()
+            .unwrap();
+        let response = key.send(request.try_clone().unwrap(), None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].body, "grant_type=authorization_code");
+        // The nonce is remembered, the next request has it from the start.
+        key.send(request, None).await.unwrap();
+        assert_eq!(server.count("/token"), 3);
+
+        // A server that keeps asking for another nonce gets a single retry.
+        let server = nonce_server(false).await;
+        let request = client.post(server.url("/token")).build().unwrap();
+        let response = key.send(request, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(server.count("/token"), 2);
+    }
+}
@@ -49,7 +49,7 @@
 //!    };
 //!
 //!    use passport_strategies::{
-//!        basic_client::{PassPortBasicClient, StateCode},
+//!        basic_client::{PassPortBasicClient, PassportResponse, StateCode},
 //!        strategies::DiscordStrategy,
 //!    };
 //!
@@ -69,19 +69,20 @@
 //!        authstate: web::Query<StateCode>,
 //!    ) -> HttpResponse {
 //!        let mut auth = auth.write().await;
-//!        /// The `response` is an enum. It can either be a failure_redirect or profile 
+//!        // The `response` is an enum. It can either be a failure_redirect or profile 
 //!        match auth.get_profile(authstate.0).await {
-//!            /// The profile is a json value containing the user profile, access_token and refresh_token.
+//!            // The profile is a json value containing the user profile, access_token and refresh_token.
 //!            Ok(response) => {
 //!                   match response {
-//!                        /// At this point you can proceed to save the profile info in the database or use the access token or refresh token to request for more user info or some other relevant info.
+//!                        // At this point you can proceed to save the profile info in the database or use the access token or refresh token to request for more user info or some other relevant info.
 //!                        PassportResponse::Profile(profile) => HttpResponse::Ok().json(profile),
-//!                        /// If the user canceled the authorization process, a redirect to i.e login page would be very convinient rather 
-//!                        /// than displaying some `Internal server error` just to say. It may not be exactly that kind of error, but can be inclusive of others.
+//!                        // If the user canceled the authorization process, a redirect to i.e login page would be very convinient rather 
+//!                        // than displaying some `Internal server error` just to say. It may not be exactly that kind of error, but can be inclusive of others.
 //!                        PassportResponse::FailureRedirect(failure) => HttpResponse::SeeOther()
 //!                        .append_header((http::header::LOCATION, failure.to_string()))
 //!                        .finish()
-//!                    },
+//!                    }
+//!            },
 //!            Err(error) => HttpResponse::BadRequest().body(error.to_string()),
 //!        }
 //!    }
@@ -108,8 +109,8 @@
 //!
 //!        let mut passport = PassPortBasicClient::default();
 //!        passport.using(
-//!            "microsoft",
-//!            MicrosoftStrategy::new(
+//!            "discord",
+//!            DiscordStrategy::new(
 //!                "<client_id>",
 //!                "<client_secret>",
 //!                vec!["<scope>"],
@@ -125,8 +126,8 @@
 //!                .wrap(Logger::default())
 //!                .app_data(Data::new(passport_clone.clone()))
 //!                .route("/signup", web::get().to(signup_get))
-//!                .route("/discord", web::get().to(msft))
-//!                .route("/<discord_redirect_endpoint>", web::get().to(authenticate_msft))
+//!                .route("/discord", web::get().to(discord))
+//!                .route("/<discord_redirect_endpoint>", web::get().to(authenticate_discord))
 //!        })
 //!        .bind("<socket_address>")? // For me, i chose port 4000 when creating Auth applications. So, it would be 127.0.0.1:4000
 //!        .run()
@@ -140,7 +141,26 @@
 /// Contains the `PassPortBasicClient` that holds the strategies.
 pub mod basic_client;
 
+// # Client Authentication
+/// Contains `ClientAuthMethod` and the `ClientKey` client assertions (RFC 7523) are signed with.
+pub mod client_auth;
+
+// # DPoP
+/// Contains `DpopKey`, the key pair DPoP (RFC 9449) proofs are signed with.
+pub mod dpop;
+
+// # Loopback
+/// Contains `LoopbackRedirect`, the loopback redirect (RFC 8252) helper for native apps.
+pub mod loopback;
+
+// # Registration
+/// Contains `ClientRegistration`, Dynamic Client Registration (RFC 7591/7592) of clients at a provider.
+pub mod registration;
+
 // # Strategies
 /// Contains all the basic strategies  `DiscordStrategy`, `GoogleStrategy`, `MicrosoftStrategy`, `GithubStrategy` and `FacebookStrategy`.
 ///  Other strategies will be added later.
 pub mod strategies;
+
+#[cfg(test)]
+mod test_server;
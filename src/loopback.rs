@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use oauth2::CsrfToken;
+use reqwest::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::basic_client::{PassPortBasicClient, PassportResponse, StateCode};
+
+/// Loopback redirect (RFC 8252) for native apps, waiting on an ephemeral `127.0.0.1` port for the provider to send the
+/// browser back. Open `authorize_url` in the user's browser, then `wait` for the callback.
+#[derive(Debug)]
+pub struct LoopbackRedirect {
+    provider: String,
+    listener: TcpListener,
+    redirect_uri: String,
+    authorize_url: String,
+}
+
+impl LoopbackRedirect {
+    const CALLBACK_PATH: &'static str = "/callback";
+    // Callback requests are a request line and a few headers, anything bigger is not one.
+    const MAX_REQUEST: usize = 16 * 1024;
+    // Connections that send nothing for this long are dropped, i.e the spare ones browsers open ahead of time.
+    const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Binds the port and makes `http://127.0.0.1:<port>/callback` the redirect uri of the strategy registered as
+    /// `provider`. The provider must allow loopback redirects on any port (i.e Google and Microsoft desktop apps).
+    pub async fn bind(passport: &mut PassPortBasicClient, provider: &str) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let redirect_uri = format!(
+            "http://127.0.0.1:{}{}",
+            listener.local_addr()?.port(),
+            Self::CALLBACK_PATH
+        );
+        passport.set_redirect_url(provider, &redirect_uri)?;
+        passport.authenticate(provider);
+        let authorize_url = passport.generate_redirect_url();
+        Ok(Self {
+            provider: provider.to_string(),
+            listener,
+            redirect_uri,
+            authorize_url,
+        })
+    }
+
+    pub fn authorize_url(&self) -> &str {
+        &self.authorize_url
+    }
+
+    pub fn redirect_uri(&self) -> &str {
+        &self.redirect_uri
+    }
+
+    /// Waits for the callback, answers the browser with a short page and resolves to what `get_profile` returned.
+    /// Requests for other paths (i.e `/favicon.ico`) get a 404 and the wait goes on.
+    pub async fn wait(
+        self,
+        passport: &mut PassPortBasicClient,
+    ) -> anyhow::Result<PassportResponse> {
+        // Each connection is read on its own, so an idle one does not hold up the callback.
+        let mut reading = tokio::task::JoinSet::new();
+        loop {
+            let (mut stream, target) = tokio::select! {
+                accepted = self.listener.accept() => {
+                    let (mut stream, _) = accepted?;
+                    reading.spawn(async move {
+                        let target =
+                            tokio::time::timeout(Self::READ_TIMEOUT, Self::read_target(&mut stream)).await;
+                        (stream, target)
+                    });
+                    continue;
+                }
+                Some(read) = reading.join_next() => match read {
+                    Ok(read) => read,
+                    Err(_) => continue,
+                },
+            };
+            let target = match target {
+                Ok(Ok(target)) => target,
+                Ok(Err(_)) => {
+                    Self::respond(&mut stream, "400 Bad Request", "Bad request.").await;
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            let url = Url::parse("http://127.0.0.1")?.join(&target)?;
+            if url.path() != Self::CALLBACK_PATH {
+                Self::respond(&mut stream, "404 Not Found", "Not found.").await;
+                continue;
+            }
+            passport.authenticate(&self.provider);
+            let response = passport.get_profile(Self::callback(&url)).await;
+            let page = match &response {
+                Ok(PassportResponse::Profile(_)) => {
+                    "Signed in. You can close this window and return to the application."
+                }
+                _ => "Sign in failed. You can close this window and return to the application.",
+            };
+            Self::respond(&mut stream, "200 OK", page).await;
+            return response;
+        }
+    }
+
+    // Request target of a `GET` request line, i.e `/callback?code=...&state=...`.
+    async fn read_target(stream: &mut TcpStream) -> anyhow::Result<String> {
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = stream.read(&mut buffer).await?;
+            if read == 0 || request.len() + read > Self::MAX_REQUEST {
+                anyhow::bail!("Incomplete callback request")
+            }
+            request.extend_from_slice(&buffer[..read]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+        match (request_line.next(), request_line.next()) {
+            (Some("GET"), Some(target)) if target.starts_with('/') => Ok(target.to_string()),
+            _ => Err(anyhow!("Not a callback request")),
+        }
+    }
+
+    fn callback(url: &Url) -> StateCode {
+        let mut params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+        StateCode {
+            state: params.remove("state").map(CsrfToken::new),
+            code: params.remove("code"),
+            params,
+        }
+    }
+
+    // The browser is only told how it went, a failed write changes nothing about the result.
+    async fn respond(stream: &mut TcpStream, status: &str, message: &str) {
+        let body = format!(
+            "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>{}</title></head><body><p>{}</p></body></html>",
+            message, message
+        );
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::GoogleStrategy;
+
+    async fn send(address: &str, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn callback_on_localhost() {
+        let mut passport = PassPortBasicClient::default();
+        passport.using(
+            "google",
+            GoogleStrategy::new(
+                "id",
+                "secret",
+                vec!["openid"],
+                "https://example.com/callback",
+                "https://example.com/login",
+            ),
+        );
+        let loopback = LoopbackRedirect::bind(&mut passport, "google")
+            .await
+            .unwrap();
+        let redirect_uri = Url::parse(loopback.redirect_uri()).unwrap();
+        assert_eq!(redirect_uri.host_str(), Some("127.0.0.1"));
+        let address = format!("127.0.0.1:{}", redirect_uri.port().unwrap());
+        let state = Url::parse(loopback.authorize_url())
+            .unwrap()
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, state)| state.into_owned())
+            .unwrap();
+
+        let browser = async {
+            // A spare connection the browser never sends on.
+            let _idle = TcpStream::connect(&address).await.unwrap();
+            let response = send(
+                &address,
+                b"GET /favicon.ico HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+            )
+            .await;
+            assert!(response.starts_with("HTTP/1.1 404"));
+            let response = send(&address, b"garbage\r\n\r\n").await;
+            assert!(response.starts_with("HTTP/1.1 400"));
+            // One byte over the limit, without the end of the headers.
+            let oversized = format!("GET /{}", "a".repeat(LoopbackRedirect::MAX_REQUEST - 4));
+            let response = send(&address, oversized.as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 400"));
+            let callback = format!(
+                "GET /callback?error=access_denied&state={} HTTP/1.1\r\nHost: 127.0.0.1\r\n\r\n",
+                state
+            );
+            let response = send(&address, callback.as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 200"));
+            assert!(response.contains("Sign in failed"));
+        };
+        let (response, ()) = tokio::join!(loopback.wait(&mut passport), browser);
+        assert!(matches!(
+            response.unwrap(),
+            PassportResponse::FailureRedirect(url) if url.as_str() == "https://example.com/login"
+        ));
+    }
+}
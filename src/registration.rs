@@ -0,0 +1,448 @@
+use anyhow::anyhow;
+
+use crate::client_auth::ClientAuthMethod;
+use crate::strategies::{Auth0Strategy, KeycloakStrategy, OktaStrategy};
+
+/// Client registered through Dynamic Client Registration (RFC 7591). It serializes to the provider's response, store it
+/// to keep the credentials and the registration access token that manages the registration (RFC 7592).
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ClientRegistration {
+    pub client_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+    /// Unix time, `0` for secrets that do not expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_secret_expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registration_access_token: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registration_client_uri: Option<String>,
+    /// Everything else the provider registered, i.e `redirect_uris` and `token_endpoint_auth_method`.
+    #[serde(flatten)]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ClientRegistration {
+    /// Registers a client at the provider's `registration_endpoint`. `metadata` is the RFC 7591 client metadata
+    /// (`redirect_uris`, `client_name`, `token_endpoint_auth_method`, ...), the `initial_access_token` is the one the
+    /// provider issued for registrations, if it requires one.
+    pub async fn register(
+        registration_endpoint: &str,
+        initial_access_token: Option<&str>,
+        metadata: serde_json::Value,
+    ) -> anyhow::Result<Self> {
+        let mut request = reqwest::Client::new()
+            .post(registration_endpoint)
+            .json(&metadata);
+        if let Some(token) = initial_access_token {
+            request = request.bearer_auth(token);
+        }
+        Self::parse(request.send().await?).await
+    }
+
+    /// Fetches the current registration from the provider.
+    pub async fn read(&mut self) -> anyhow::Result<()> {
+        let (uri, token) = self.management()?;
+        let response = reqwest::Client::new()
+            .get(uri)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        self.replace(Self::parse(response).await?);
+        Ok(())
+    }
+
+    /// Replaces the registered metadata. Fields left out of `metadata` are reset by the provider, so send the full set.
+    /// The provider may rotate the secret and the registration access token, store the registration again afterwards.
+    pub async fn update(&mut self, mut metadata: serde_json::Value) -> anyhow::Result<()> {
+        let (uri, token) = self.management()?;
+        metadata
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("Client metadata must be a JSON object"))?
+            .insert(String::from("client_id"), serde_json::json!(self.client_id));
+        let response = reqwest::Client::new()
+            .put(uri)
+            .bearer_auth(token)
+            .json(&metadata)
+            .send()
+            .await?;
+        self.replace(Self::parse(response).await?);
+        Ok(())
+    }
+
+    /// Deletes the registration, the client credentials stop working.
+    pub async fn delete(self) -> anyhow::Result<()> {
+        let (uri, token) = self.management()?;
+        let response = reqwest::Client::new()
+            .delete(uri)
+            .bearer_auth(token)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            anyhow::bail!(Self::error(response).await)
+        }
+        Ok(())
+    }
+
+    /// Whether the client secret has expired and the client must be registered again.
+    pub fn secret_expired(&self) -> bool {
+        self.client_secret_expires_at.is_some_and(|expires_at| {
+            expires_at != 0 && expires_at <= chrono::Utc::now().timestamp()
+        })
+    }
+
+    /// Client authentication the registration asked for. `None` for `private_key_jwt`, the key has to be handed to the
+    /// strategy with `client_auth_method`, and for methods this crate does not know.
+    pub fn client_auth_method(&self) -> Option<ClientAuthMethod> {
+        match self.metadata.get("token_endpoint_auth_method")?.as_str()? {
+            "client_secret_basic" => Some(ClientAuthMethod::ClientSecretBasic),
+            "client_secret_post" => Some(ClientAuthMethod::ClientSecretPost),
+            "client_secret_jwt" => Some(ClientAuthMethod::ClientSecretJwt),
+            "tls_client_auth" => Some(ClientAuthMethod::TlsClientAuth),
+            "none" => Some(ClientAuthMethod::None),
+            _ => None,
+        }
+    }
+
+    /// `KeycloakStrategy` for the registered client, redirecting to its first registered redirect uri. Fails for
+    /// `private_key_jwt` registrations, the strategy needs the key.
+    pub fn keycloak(
+        &self,
+        server: &str,
+        realm: &str,
+        scopes: Vec<&str>,
+        failure_redirect: &str,
+    ) -> anyhow::Result<KeycloakStrategy> {
+        let strategy = KeycloakStrategy::new(
+            server,
+            realm,
+            &self.client_id,
+            self.secret(),
+            scopes,
+            self.redirect_uri()?,
+            failure_redirect,
+        );
+        Ok(strategy.client_auth_method(self.strategy_auth_method()?))
+    }
+
+    /// `OktaStrategy` for the registered client, redirecting to its first registered redirect uri. Fails for
+    /// `private_key_jwt` registrations, the strategy needs the key.
+    pub fn okta(
+        &self,
+        domain: &str,
+        authorization_server: Option<&str>,
+        scopes: Vec<&str>,
+        failure_redirect: &str,
+    ) -> anyhow::Result<OktaStrategy> {
+        let strategy = OktaStrategy::new(
+            domain,
+            authorization_server,
+            &self.client_id,
+            self.secret(),
+            scopes,
+            self.redirect_uri()?,
+            failure_redirect,
+        );
+        Ok(strategy.client_auth_method(self.strategy_auth_method()?))
+    }
+
+    /// `Auth0Strategy` for the registered client, redirecting to its first registered redirect uri. Fails for
+    /// `private_key_jwt` registrations, the strategy needs the key.
+    pub fn auth0(
+        &self,
+        domain: &str,
+        scopes: Vec<&str>,
+        failure_redirect: &str,
+    ) -> anyhow::Result<Auth0Strategy> {
+        let strategy = Auth0Strategy::new(
+            domain,
+            &self.client_id,
+            self.secret(),
+            scopes,
+            self.redirect_uri()?,
+            failure_redirect,
+        );
+        Ok(strategy.client_auth_method(self.strategy_auth_method()?))
+    }
+
+    // RFC 7591 defaults to `client_secret_basic`. Registrations whose method needs more than the registration itself
+    // (i.e a private key) cannot be turned into a strategy here.
+    fn strategy_auth_method(&self) -> anyhow::Result<ClientAuthMethod> {
+        match self.metadata.get("token_endpoint_auth_method") {
+            None => Ok(ClientAuthMethod::ClientSecretBasic),
+            Some(method) => self.client_auth_method().ok_or_else(|| {
+                anyhow!(
+                    "Registered with {}, create the strategy and set its client_auth_method instead",
+                    method
+                )
+            }),
+        }
+    }
+
+    fn secret(&self) -> &str {
+        self.client_secret.as_deref().unwrap_or_default()
+    }
+
+    fn redirect_uri(&self) -> anyhow::Result<&str> {
+        self.metadata
+            .get("redirect_uris")
+            .and_then(|uris| uris.get(0))
+            .and_then(|uri| uri.as_str())
+            .ok_or_else(|| anyhow!("Registration without redirect_uris"))
+    }
+
+    fn management(&self) -> anyhow::Result<(&str, &str)> {
+        match (
+            self.registration_client_uri.as_deref(),
+            self.registration_access_token.as_deref(),
+        ) {
+            (Some(uri), Some(token)) => Ok((uri, token)),
+            _ => anyhow::bail!("The provider did not return a registration access token and uri"),
+        }
+    }
+
+    // Providers may leave out the registration access token and uri when they do not change.
+    fn replace(&mut self, mut registration: Self) {
+        if registration.registration_access_token.is_none() {
+            registration.registration_access_token = self.registration_access_token.take();
+        }
+        if registration.registration_client_uri.is_none() {
+            registration.registration_client_uri = self.registration_client_uri.take();
+        }
+        *self = registration;
+    }
+
+    async fn parse(response: reqwest::Response) -> anyhow::Result<Self> {
+        if !response.status().is_success() {
+            anyhow::bail!(Self::error(response).await)
+        }
+        Ok(response.json::<Self>().await?)
+    }
+
+    // RFC 7591 errors carry `error` (i.e `invalid_redirect_uri`) and `error_description`.
+    async fn error(response: reqwest::Response) -> String {
+        let text = response.text().await.unwrap_or_default();
+        match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(json) => match (json["error"].as_str(), json["error_description"].as_str()) {
+                (Some(error), Some(description)) => format!("{}: {}", error, description),
+                (Some(error), None) => error.to_string(),
+                _ => text,
+            },
+            Err(_) => text,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies::Strategy;
+    use crate::test_server::{Response, TestServer};
+
+    // Registration endpoint at `/register` managing a single client at `/register/abc`. Reads leave the registration
+    // access token out, updates rotate it.
+    async fn registration_server() -> TestServer {
+        TestServer::start(|request| {
+            let client_uri = format!("http://{}/register/abc", request.header("host").unwrap());
+            let authorized = |token: &str| request.header("authorization") == Some(token);
+            let mut metadata = request.json();
+            match (request.method.as_str(), request.path.as_str()) {
+                ("POST", "/register") if !authorized("Bearer initial") => {
+                    Response::json(401, serde_json::json!({ "error": "invalid_token" }))
+                }
+                ("POST", "/register") if metadata["redirect_uris"].get(0).is_none() => {
+                    Response::json(
+                        400,
+                        serde_json::json!({
+                            "error": "invalid_redirect_uri",
+                            "error_description": "At least one redirect uri is required",
+                        }),
+                    )
+                }
+                ("POST", "/register") => {
+                    metadata["client_id"] = serde_json::json!("abc");
+                    metadata["client_secret"] = serde_json::json!("secret");
+                    metadata["client_secret_expires_at"] = serde_json::json!(0);
+                    metadata["registration_access_token"] = serde_json::json!("token-1");
+                    metadata["registration_client_uri"] = serde_json::json!(client_uri);
+                    Response::json(201, metadata)
+                }
+                ("GET", "/register/abc") if authorized("Bearer token-1") => Response::json(
+                    200,
+                    serde_json::json!({
+                        "client_id": "abc",
+                        "client_secret": "secret",
+                        "client_name": "Read back",
+                        "redirect_uris": ["https://example.com/callback"],
+                    }),
+                ),
+                ("PUT", "/register/abc") if authorized("Bearer token-1") => {
+                    metadata["client_secret"] = serde_json::json!("rotated");
+                    metadata["registration_access_token"] = serde_json::json!("token-2");
+                    metadata["registration_client_uri"] = serde_json::json!(client_uri);
+                    Response::json(200, metadata)
+                }
+                ("DELETE", "/register/abc") if authorized("Bearer token-2") => {
+                    Response::text(204, "")
+                }
+                _ => Response::json(401, serde_json::json!({ "error": "invalid_token" })),
+            }
+        })
+        .await
+    }
+
+    fn metadata(method: &str) -> serde_json::Value {
+        serde_json::json!({
+            "client_name": "Example",
+            "redirect_uris": ["https://example.com/callback"],
+            "token_endpoint_auth_method": method,
+        })
+    }
+
+    #[tokio::test]
+    async fn registration_lifecycle() {
+        let server = registration_server().await;
+        let mut registration = ClientRegistration::register(
+            &server.url("/register"),
+            Some("initial"),
+            metadata("client_secret_post"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(server.requests()[0].json(), metadata("client_secret_post"));
+        assert_eq!(registration.client_id, "abc");
+        assert_eq!(registration.client_secret.as_deref(), Some("secret"));
+        assert_eq!(registration.metadata["client_name"], "Example");
+        assert!(!registration.secret_expired());
+
+        // The provider left the registration access token and uri out, the ones we had are kept.
+        registration.read().await.unwrap();
+        assert_eq!(registration.metadata["client_name"], "Read back");
+        assert_eq!(
+            registration.registration_access_token.as_deref(),
+            Some("token-1")
+        );
+        assert_eq!(
+            registration.registration_client_uri,
+            Some(server.url("/register/abc"))
+        );
+
+        registration
+            .update(metadata("client_secret_basic"))
+            .await
+            .unwrap();
+        let update = server.requests()[2].json();
+        assert_eq!(update["client_id"], "abc");
+        assert_eq!(update["token_endpoint_auth_method"], "client_secret_basic");
+        assert_eq!(registration.client_secret.as_deref(), Some("rotated"));
+        assert_eq!(
+            registration.registration_access_token.as_deref(),
+            Some("token-2")
+        );
+        assert!(registration
+            .update(serde_json::json!(["not", "an", "object"]))
+            .await
+            .is_err());
+
+        // Stored and restored, the registration still manages the client.
+        let registration = serde_json::from_value::<ClientRegistration>(
+            serde_json::to_value(&registration).unwrap(),
+        )
+        .unwrap();
+        registration.delete().await.unwrap();
+        assert_eq!(server.requests().last().unwrap().method, "DELETE");
+    }
+
+    #[tokio::test]
+    async fn registration_errors() {
+        let server = registration_server().await;
+        let error = ClientRegistration::register(
+            &server.url("/register"),
+            Some("initial"),
+            serde_json::json!({ "client_name": "Example" }),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "invalid_redirect_uri: At least one redirect uri is required"
+        );
+        let error = ClientRegistration::register(&server.url("/register"), None, metadata("none"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "invalid_token");
+
+        let mut unmanaged = serde_json::from_value::<ClientRegistration>(serde_json::json!({
+            "client_id": "abc",
+            "client_secret_expires_at": 1,
+        }))
+        .unwrap();
+        assert!(unmanaged.secret_expired());
+        assert!(unmanaged.read().await.is_err());
+    }
+
+    #[test]
+    fn registered_strategies() {
+        let registration = |method: Option<&str>| {
+            let mut registration = serde_json::json!({
+                "client_id": "abc",
+                "client_secret": "secret",
+                "redirect_uris": ["https://example.com/callback"],
+            });
+            if let Some(method) = method {
+                registration["token_endpoint_auth_method"] = serde_json::json!(method);
+            }
+            serde_json::from_value::<ClientRegistration>(registration).unwrap()
+        };
+        let keycloak = registration(Some("client_secret_post"))
+            .keycloak(
+                "https://sso.example.com",
+                "demo",
+                vec!["openid"],
+                "https://example.com/login",
+            )
+            .unwrap();
+        assert_eq!(
+            Strategy::client_auth_method(&keycloak).name(),
+            "client_secret_post"
+        );
+        assert_eq!(keycloak.redirect_url(), "https://example.com/callback");
+        assert_eq!(keycloak.client_secret(), "secret");
+        let okta = registration(None)
+            .okta(
+                "dev-123456.okta.com",
+                None,
+                vec!["openid"],
+                "https://example.com/login",
+            )
+            .unwrap();
+        assert_eq!(
+            Strategy::client_auth_method(&okta).name(),
+            "client_secret_basic"
+        );
+
+        // The key of a `private_key_jwt` client is not part of the registration.
+        let error = registration(Some("private_key_jwt"))
+            .auth0(
+                "tenant.auth0.com",
+                vec!["openid"],
+                "https://example.com/login",
+            )
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Registered with \"private_key_jwt\", create the strategy and set its client_auth_method instead"
+        );
+        assert!(registration(Some("self_signed_tls_client_auth"))
+            .keycloak(
+                "https://sso.example.com",
+                "demo",
+                vec![],
+                "https://example.com/login"
+            )
+            .is_err());
+    }
+}
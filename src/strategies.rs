@@ -1,8 +1,21 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
 
+use anyhow::anyhow;
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use colored::Colorize;
-use oauth2::{Scope, TokenUrl};
-use reqwest::Url;
+use hmac::{Hmac, Mac};
+use oauth2::{AuthType, CsrfToken, PkceCodeChallenge, Scope, TokenResponse, TokenUrl};
+use reqwest::header::{HeaderMap, HeaderValue};
+use reqwest::{Method, Url};
+use sha2::{Digest, Sha256};
+
+use crate::basic_client::{PassportResponse, PassportTokenResponse, StateCode};
+use crate::client_auth::{ClientAuthMethod, ClientCertificate};
+use crate::dpop::DpopKey;
 
 #[derive(Debug, Clone)]
 pub struct FacebookStrategy {
@@ -85,8 +98,6 @@ impl Default for GithubStrategy {
     }
 }
 
-
-
 #[derive(Debug, Clone)]
 pub struct DiscordStrategy {
     pub(crate) client_id: String,
@@ -114,7 +125,6 @@ impl Default for DiscordStrategy {
     }
 }
 
-
 #[derive(Clone, Debug)]
 pub struct MicrosoftStrategy {
     pub(crate) client_id: String,
@@ -132,117 +142,4386 @@ impl Default for MicrosoftStrategy {
         Self {
             client_id: String::new(),
             client_secret: String::new(),
-            auth_uri: String::from("https://login.microsoftonline.com/common/oauth2/v2.0/authorize?prompt=select_account"),
+            auth_uri: String::from(
+                "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
+            ),
             scopes: Vec::new(),
             token_uri: String::from("https://login.microsoftonline.com/common/oauth2/v2.0/token"),
             request_uri: String::from("https://graph.microsoft.com/v1.0/me"),
             redirect_uri: String::new(),
-            failure_redirect: String::new()
+            failure_redirect: String::new(),
         }
     }
 }
 
-pub trait Strategy: Debug {
-    fn redirect_url(&self) -> String;
-    fn request_uri(&self) -> String;
-    fn scopes(&self) -> Vec<Scope>;
-    fn client_id(&self) -> String;
-    fn client_secret(&self) -> String;
-    fn auth_url(&self) -> String;
-    fn token_url(&self) -> Option<TokenUrl>;
-    fn failure_redirect(&self) -> Url;
+impl MicrosoftStrategy {
+    /// Signs in through a tenant (id or domain, i.e `contoso.onmicrosoft.com`) instead of `common`.
+    /// App-only tokens (`client_credentials`) need it, the client credentials grant is not available on `common`.
+    pub fn tenant(mut self, tenant: &str) -> Self {
+        self.auth_uri = format!(
+            "https://login.microsoftonline.com/{}/oauth2/v2.0/authorize",
+            tenant
+        );
+        self.token_uri = format!(
+            "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+            tenant
+        );
+        self
+    }
 }
 
-macro_rules! new_strategy {
-    ($($name:ty),*) => {
-        $(
-            impl $name {
-                pub fn new(
-                    client_id: &str,
-                    client_secret: &str,
-                    scopes: Vec<&str>,
-                    redirect_uri: &str,
-                    failure_redirect: &str
-                ) -> Self {
-                    let mut strategy = Self::default();
-                    strategy.client_id.push_str(client_id);
-                    strategy.client_secret.push_str(client_secret);
-                    strategy.redirect_uri.push_str(redirect_uri);
-                    strategy.failure_redirect.push_str(failure_redirect);
-                    strategy
-                        .scopes
-                        .extend(scopes.iter().map(ToString::to_string).map(Scope::new));
-                    strategy
-                }
-            }
-        )*
-    };
+#[derive(Clone, Debug)]
+pub struct LinkedInStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
 }
 
-macro_rules! strategy {
-    ($($name:ty),*) => {
-        $(
-            impl Strategy for $name {
-                fn request_uri(&self) -> String {
-                    self.request_uri.clone()
-                }
-                fn scopes(&self) -> Vec<Scope> {
-                    self.scopes.clone()
-                }
-                fn client_id(&self) -> String {
-                    self.client_id.clone()
-                }
+impl Default for LinkedInStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://www.linkedin.com/oauth/v2/authorization"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://www.linkedin.com/oauth/v2/accessToken"),
+            request_uri: String::from("https://api.linkedin.com/v2/userinfo"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
 
-                fn client_secret(&self) -> String {
-                    self.client_secret.clone()
-                }
+impl LinkedInStrategy {
+    const SCOPES: [&'static str; 3] = ["openid", "profile", "email"];
 
-                fn auth_url(&self) -> String {
-                    self.auth_uri.clone()
-                }
+    /// Uses "Sign In with LinkedIn using OpenID Connect", the `openid profile email` scopes are always requested.
+    pub fn new(
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let mut strategy = Self::default();
+        strategy.client_id.push_str(client_id);
+        strategy.client_secret.push_str(client_secret);
+        strategy.redirect_uri.push_str(redirect_uri);
+        strategy.failure_redirect.push_str(failure_redirect);
+        strategy.scopes.extend(
+            Self::SCOPES
+                .iter()
+                .chain(scopes.iter().filter(|scope| !Self::SCOPES.contains(scope)))
+                .map(ToString::to_string)
+                .map(Scope::new),
+        );
+        strategy
+    }
+}
 
-                fn redirect_url(&self) -> String {
-                    self.redirect_uri.clone()
-                }
+#[derive(Clone, Debug)]
+pub struct SlackStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) authorize_params: Vec<(String, String)>,
+    pub(crate) allowed_teams: Vec<String>,
+}
 
-                fn failure_redirect(&self) -> Url {
-                    match self.failure_redirect.parse::<reqwest::Url>() {
-                        Ok(url) =>  url,
-                        Err(err) => panic!("{}{:?}", "Invalid Url".bold().red(), err)
-                    }
-                }
+impl Default for SlackStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://slack.com/openid/connect/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://slack.com/api/openid.connect.token"),
+            request_uri: String::from("https://slack.com/api/openid.connect.userInfo"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            authorize_params: Vec::new(),
+            allowed_teams: Vec::new(),
+        }
+    }
+}
 
-                fn token_url(&self) -> Option<TokenUrl> {
-                    match TokenUrl::new(self.token_uri.clone()) {
-                        Ok(token) => Some(token),
-                        Err(err) => panic!("{}{}", "Invalid Token URL: ".bold().red(), err),
-                    }
-                }
+impl SlackStrategy {
+    const TEAM_ID: &'static str = "https://slack.com/team_id";
+    const TEAM_NAME: &'static str = "https://slack.com/team_name";
+    const TEAM_DOMAIN: &'static str = "https://slack.com/team_domain";
+    // Largest first, Slack only sends the sizes the workspace icon exists in.
+    const TEAM_IMAGES: [&'static str; 6] = [
+        "https://slack.com/team_image_230",
+        "https://slack.com/team_image_132",
+        "https://slack.com/team_image_88",
+        "https://slack.com/team_image_68",
+        "https://slack.com/team_image_44",
+        "https://slack.com/team_image_34",
+    ];
+
+    /// Skips the workspace picker when the user is signed in to the given team id.
+    pub fn team(mut self, team_id: &str) -> Self {
+        self.authorize_params
+            .push((String::from("team"), team_id.to_string()));
+        self
+    }
+
+    /// Only lets users of these team ids sign in. All workspaces are allowed when empty.
+    pub fn allowed_teams(mut self, team_ids: Vec<&str>) -> Self {
+        self.allowed_teams
+            .extend(team_ids.iter().map(ToString::to_string));
+        self
+    }
+
+    fn team_profile(&self, mut profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        // Slack answers errors with `200 OK` and `"ok": false`.
+        if profile["ok"] == serde_json::json!(false) {
+            anyhow::bail!(
+                "Slack userInfo failed: {}",
+                profile["error"].as_str().unwrap_or("unknown_error")
+            )
+        }
+        let team_id = profile[Self::TEAM_ID]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing team id in Slack profile"))?
+            .to_string();
+        if !self.allowed_teams.is_empty() && !self.allowed_teams.contains(&team_id) {
+            anyhow::bail!("Slack workspace {} is not allowed", team_id)
+        }
+        profile["team_id"] = serde_json::json!(team_id);
+        profile["team_name"] = profile[Self::TEAM_NAME].clone();
+        profile["team_domain"] = profile[Self::TEAM_DOMAIN].clone();
+        profile["team_avatar"] = Self::TEAM_IMAGES
+            .iter()
+            .map(|key| profile[key].clone())
+            .find(|image| image.is_string())
+            .unwrap_or_default();
+        Ok(profile)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TwitchStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for TwitchStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://id.twitch.tv/oauth2/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://id.twitch.tv/oauth2/token"),
+            request_uri: String::from("https://api.twitch.tv/helix/users"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MastodonStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) client_name: String,
+    pub(crate) website: String,
+    pub(crate) instance: String,
+    // Client credentials per instance domain, shared by every clone of the strategy.
+    pub(crate) registrations: Arc<Mutex<HashMap<String, (String, String)>>>,
+}
+
+impl Default for MastodonStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::new(),
+            scopes: Vec::new(),
+            token_uri: String::new(),
+            request_uri: String::new(),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            client_name: String::new(),
+            website: String::new(),
+            instance: String::new(),
+            registrations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl MastodonStrategy {
+    /// Describes the app registered on every instance the users sign in with. The strategy itself is only a template,
+    /// pass the one returned by [`MastodonStrategy::instance`] to `PassPortBasicClient::using`.
+    pub fn new(
+        client_name: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let mut strategy = Self::default();
+        strategy.client_name.push_str(client_name);
+        strategy.redirect_uri.push_str(redirect_uri);
+        strategy.failure_redirect.push_str(failure_redirect);
+        strategy
+            .scopes
+            .extend(scopes.iter().map(ToString::to_string).map(Scope::new));
+        strategy
+    }
+
+    /// Homepage shown to the user on the authorization page of the instance.
+    pub fn website(mut self, website: &str) -> Self {
+        self.website = website.to_string();
+        self
+    }
+
+    /// Seeds the cache with credentials registered earlier (i.e loaded from a database) so the app is not registered again.
+    pub fn with_registration(self, instance: &str, client_id: &str, client_secret: &str) -> Self {
+        if let Ok(domain) = Self::domain(instance) {
+            self.registrations
+                .lock()
+                .unwrap()
+                .insert(domain, (client_id.to_string(), client_secret.to_string()));
+        }
+        self
+    }
+
+    /// Client id and secret registered on the instance, to persist them across restarts.
+    pub fn registration(&self, instance: &str) -> Option<(String, String)> {
+        let domain = Self::domain(instance).ok()?;
+        self.registrations.lock().unwrap().get(&domain).cloned()
+    }
+
+    /// Strategy for the user-entered instance (i.e `mastodon.social`). The app is registered through
+    /// `POST /api/v1/apps` the first time the instance is seen, later calls reuse the cached credentials. IP addresses
+    /// and hosts without a dot are refused.
+    pub async fn instance(&self, instance: &str) -> anyhow::Result<Self> {
+        let domain = Self::domain(instance)?;
+        let cached = self.registrations.lock().unwrap().get(&domain).cloned();
+        let (client_id, client_secret) = match cached {
+            Some(credentials) => credentials,
+            None => {
+                let credentials = self.register(&domain).await?;
+                self.registrations
+                    .lock()
+                    .unwrap()
+                    .insert(domain.clone(), credentials.clone());
+                credentials
             }
-        )*
-    };
+        };
+        let mut strategy = self.clone();
+        strategy.client_id = client_id;
+        strategy.client_secret = client_secret;
+        strategy.auth_uri = format!("https://{}/oauth/authorize", domain);
+        strategy.token_uri = format!("https://{}/oauth/token", domain);
+        strategy.request_uri = format!("https://{}/api/v1/accounts/verify_credentials", domain);
+        strategy.instance = domain;
+        Ok(strategy)
+    }
+
+    async fn register(&self, domain: &str) -> anyhow::Result<(String, String)> {
+        let scopes = self
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut form = vec![
+            ("client_name", self.client_name.as_str()),
+            ("redirect_uris", self.redirect_uri.as_str()),
+            ("scopes", scopes.as_str()),
+        ];
+        if !self.website.is_empty() {
+            form.push(("website", self.website.as_str()));
+        }
+        let response = reqwest::Client::new()
+            .post(format!("https://{}/api/v1/apps", domain))
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            anyhow::bail!(
+                "Registering the app on {} failed: {}",
+                domain,
+                response.text().await?
+            )
+        }
+        let app = response.json::<serde_json::Value>().await?;
+        match (app["client_id"].as_str(), app["client_secret"].as_str()) {
+            (Some(client_id), Some(client_secret)) => {
+                Ok((client_id.to_string(), client_secret.to_string()))
+            }
+            _ => anyhow::bail!("{} returned no client credentials", domain),
+        }
+    }
+
+    // Instance domains are user input, only a bare host name (and port) is accepted.
+    fn domain(instance: &str) -> anyhow::Result<String> {
+        let domain = instance.trim().to_lowercase();
+        let domain = domain
+            .strip_prefix("https://")
+            .unwrap_or(&domain)
+            .trim_end_matches('/');
+        let valid = domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'));
+        // Only public domain names, IP literals and single label hosts (i.e `localhost`) point at no instance.
+        let url = Url::parse(&format!("https://{}", domain))
+            .ok()
+            .filter(|_| valid);
+        let host = url
+            .as_ref()
+            .and_then(Url::domain)
+            .filter(|host| host.contains('.') && host.split('.').all(|label| !label.is_empty()));
+        match (&url, host) {
+            (Some(url), Some(host)) => Ok(match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            }),
+            _ => anyhow::bail!("Invalid Mastodon instance: {}", instance),
+        }
+    }
 }
 
-strategy!(
-    GithubStrategy,
-    GoogleStrategy,
-    MicrosoftStrategy,
-    FacebookStrategy,
-    DiscordStrategy
-);
+#[derive(Clone, Debug)]
+pub struct AtlassianStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) resources_uri: String,
+}
 
-new_strategy!(
-    GithubStrategy,
-    GoogleStrategy,
-    MicrosoftStrategy,
-    FacebookStrategy,
-    DiscordStrategy
-);
+impl Default for AtlassianStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://auth.atlassian.com/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://auth.atlassian.com/oauth/token"),
+            request_uri: String::from("https://api.atlassian.com/me"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            resources_uri: String::from(
+                "https://api.atlassian.com/oauth/token/accessible-resources",
+            ),
+        }
+    }
+}
 
-// impl<C> Message for Strategies<C>
-// where
-//     C: std::marker::Unpin + ToString + Clone + Send + 'static,
-//     Self: Strategy<C> + std::marker::Unpin + Send + 'static,
-// {
-//     type Result = Option<PassPortBasicClient<Self, C>>;
-// }
+impl AtlassianStrategy {
+    // The cloud sites (Jira, Confluence) the user granted the app access to, API calls go through their id.
+    async fn accessible_resources(
+        &self,
+        mut profile: serde_json::Value,
+        token: &PassportTokenResponse,
+    ) -> anyhow::Result<serde_json::Value> {
+        let response = reqwest::Client::new()
+            .get(&self.resources_uri)
+            .bearer_auth(token.access_token().secret())
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        let resources = response.json::<Vec<serde_json::Value>>().await?;
+        profile["sites"] = resources
+            .iter()
+            .map(|site| {
+                serde_json::json!({
+                    "id": site["id"],
+                    "name": site["name"],
+                    "url": site["url"],
+                    "scopes": site["scopes"],
+                })
+            })
+            .collect();
+        Ok(profile)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DropboxStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for DropboxStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://www.dropbox.com/oauth2/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://api.dropboxapi.com/oauth2/token"),
+            request_uri: String::from("https://api.dropboxapi.com/2/users/get_current_account"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SpotifyStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) show_dialog: bool,
+}
+
+impl Default for SpotifyStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://accounts.spotify.com/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://accounts.spotify.com/api/token"),
+            request_uri: String::from("https://api.spotify.com/v1/me"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            show_dialog: false,
+        }
+    }
+}
+
+impl SpotifyStrategy {
+    /// Asks the user to approve the app again even if they already did, i.e to switch accounts.
+    pub fn show_dialog(mut self, show_dialog: bool) -> Self {
+        self.show_dialog = show_dialog;
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RedditStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) user_agent: String,
+}
+
+impl Default for RedditStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://www.reddit.com/api/v1/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://www.reddit.com/api/v1/access_token"),
+            request_uri: String::from("https://oauth.reddit.com/api/v1/me"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            user_agent: String::new(),
+        }
+    }
+}
+
+impl RedditStrategy {
+    /// Reddit throttles generic User-Agents, describe the app as `<platform>:<app id>:<version> (by /u/<username>)`.
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    // Falls back to a User-Agent built from the client id when none was configured.
+    fn headers(&self) -> HeaderMap {
+        let user_agent = match self.user_agent.is_empty() {
+            true => format!(
+                "web:{}:v{} (passport-strategies)",
+                self.client_id,
+                env!("CARGO_PKG_VERSION")
+            ),
+            false => self.user_agent.clone(),
+        };
+        let mut headers = HeaderMap::new();
+        if let Ok(user_agent) = HeaderValue::from_str(&user_agent) {
+            headers.insert(reqwest::header::USER_AGENT, user_agent);
+        }
+        headers
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NotionStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for NotionStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://api.notion.com/v1/oauth/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://api.notion.com/v1/oauth/token"),
+            request_uri: String::from("https://api.notion.com/v1/users/me"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+impl NotionStrategy {
+    // The user who installed the integration and the workspace they installed it in.
+    fn owner(&self, token: &PassportTokenResponse) -> anyhow::Result<serde_json::Value> {
+        let fields = token.extra_fields();
+        let owner = fields
+            .get("owner")
+            .ok_or_else(|| anyhow!("Missing owner in Notion token response"))?;
+        if owner["type"] != "user" {
+            anyhow::bail!("Notion integration is not owned by a user")
+        }
+        let mut profile = owner["user"].clone();
+        profile["workspace"] = serde_json::json!({
+            "id": fields.get("workspace_id"),
+            "name": fields.get("workspace_name"),
+            "icon": fields.get("workspace_icon"),
+        });
+        profile["bot_id"] = fields.get("bot_id").cloned().unwrap_or_default();
+        Ok(profile)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct StravaStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for StravaStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://www.strava.com/oauth/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://www.strava.com/oauth/token"),
+            request_uri: String::from("https://www.strava.com/api/v3/athlete"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+impl StravaStrategy {
+    /// Strava expects the scopes comma delimited, they are sent as a single scope.
+    pub fn new(
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let mut strategy = Self::default();
+        strategy.client_id.push_str(client_id);
+        strategy.client_secret.push_str(client_secret);
+        strategy.redirect_uri.push_str(redirect_uri);
+        strategy.failure_redirect.push_str(failure_redirect);
+        if !scopes.is_empty() {
+            strategy.scopes.push(Scope::new(scopes.join(",")));
+        }
+        strategy
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShopifyAccessMode {
+    /// Token bound to the shop, it does not expire and carries no user.
+    Offline,
+    /// Token bound to the user who authorized the app (`grant_options[]=per-user`), it expires with their session.
+    Online,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShopifyStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) access_mode: ShopifyAccessMode,
+    pub(crate) shop: String,
+}
+
+impl ShopifyStrategy {
+    // How far the `timestamp` of a signed query may be from our clock, in seconds.
+    const HMAC_MAX_AGE: i64 = 300;
+
+    /// Describes the app installed on every shop. The strategy itself is only a template, pass the one returned by
+    /// [`ShopifyStrategy::shop`] to `PassPortBasicClient::using`. Shopify expects the scopes comma delimited.
+    pub fn new(
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+        access_mode: ShopifyAccessMode,
+    ) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_uri: String::new(),
+            scopes: match scopes.is_empty() {
+                true => Vec::new(),
+                false => vec![Scope::new(scopes.join(","))],
+            },
+            request_uri: String::new(),
+            token_uri: String::new(),
+            redirect_uri: redirect_uri.to_string(),
+            failure_redirect: failure_redirect.to_string(),
+            access_mode,
+            shop: String::new(),
+        }
+    }
+
+    /// Strategy for the given shop (i.e `my-shop.myshopify.com` or `my-shop`), as sent in the `shop` query parameter.
+    pub fn shop(&self, shop: &str) -> anyhow::Result<Self> {
+        let shop = Self::shop_domain(shop)?;
+        let mut strategy = self.clone();
+        strategy.auth_uri = format!("https://{}/admin/oauth/authorize", shop);
+        strategy.token_uri = format!("https://{}/admin/oauth/access_token", shop);
+        strategy.request_uri = format!("https://{}/admin/api/2024-01/shop.json", shop);
+        strategy.shop = shop;
+        Ok(strategy)
+    }
+
+    // Shop names come from the query string, only `{name}.myshopify.com` host names are accepted.
+    fn shop_domain(shop: &str) -> anyhow::Result<String> {
+        let shop = shop.trim().to_lowercase();
+        let shop = match shop.ends_with(".myshopify.com") {
+            true => shop,
+            false => format!("{}.myshopify.com", shop),
+        };
+        let name = shop.strip_suffix(".myshopify.com").unwrap_or_default();
+        let valid = name
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !valid {
+            anyhow::bail!("Invalid Shopify shop: {}", shop)
+        }
+        Ok(shop)
+    }
+
+    /// Verifies the `hmac` Shopify adds to the query of the callback and of the app install request, that its
+    /// `timestamp` is recent and that the `shop` in it is the one of this strategy.
+    pub fn verify_hmac(&self, query: &StateCode) -> anyhow::Result<()> {
+        self.verify_signature(query)?;
+
+        // A signed query stays valid forever, only a recent one is accepted.
+        let timestamp = query
+            .params
+            .get("timestamp")
+            .and_then(|timestamp| timestamp.parse::<i64>().ok())
+            .ok_or_else(|| anyhow!("Missing timestamp in Shopify query"))?;
+        if (chrono::Utc::now().timestamp() - timestamp).abs() > Self::HMAC_MAX_AGE {
+            anyhow::bail!("Outdated Shopify query")
+        }
+
+        let shop = query
+            .params
+            .get("shop")
+            .ok_or_else(|| anyhow!("Missing shop in Shopify query"))?;
+        if Self::shop_domain(shop)? != self.shop {
+            anyhow::bail!("Unexpected Shopify shop: {}", shop)
+        }
+        Ok(())
+    }
+
+    fn verify_signature(&self, query: &StateCode) -> anyhow::Result<()> {
+        let hmac = query
+            .params
+            .get("hmac")
+            .and_then(|hmac| hex::decode(hmac).ok())
+            .ok_or_else(|| anyhow!("Missing Shopify hmac"))?;
+        // The message is every parameter but the hmac, sorted and joined as a query string with `%`, `&` and `=` escaped.
+        let escape = |text: &str, key: bool| {
+            let text = text.replace('%', "%25").replace('&', "%26");
+            match key {
+                true => text.replace('=', "%3D"),
+                false => text,
+            }
+        };
+        let mut params = query
+            .params
+            .iter()
+            .filter(|(key, _)| key.as_str() != "hmac")
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        if let Some(state) = query.state.as_ref() {
+            params.push((String::from("state"), state.secret().clone()));
+        }
+        if let Some(code) = query.code.as_ref() {
+            params.push((String::from("code"), code.clone()));
+        }
+        params.sort();
+        let message = params
+            .iter()
+            .map(|(key, value)| format!("{}={}", escape(key, true), escape(value, false)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.client_secret.as_bytes())?;
+        mac.update(message.as_bytes());
+        mac.verify_slice(&hmac)
+            .map_err(|_| anyhow!("Shopify hmac verification failed"))
+    }
+
+    // Online tokens carry the user who authorized the app, offline ones only identify the shop.
+    fn shop_profile(&self, token: &PassportTokenResponse) -> anyhow::Result<serde_json::Value> {
+        let mut profile = match self.access_mode {
+            ShopifyAccessMode::Online => token
+                .extra_fields()
+                .get("associated_user")
+                .cloned()
+                .ok_or_else(|| anyhow!("Missing associated_user in Shopify token response"))?,
+            ShopifyAccessMode::Offline => serde_json::json!({}),
+        };
+        profile["shop"] = serde_json::json!(self.shop);
+        profile["access_mode"] = serde_json::json!(match self.access_mode {
+            ShopifyAccessMode::Online => "online",
+            ShopifyAccessMode::Offline => "offline",
+        });
+        Ok(profile)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum AmazonRegion {
+    NorthAmerica,
+    Europe,
+    FarEast,
+}
+
+#[derive(Clone, Debug)]
+pub struct AmazonStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for AmazonStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://www.amazon.com/ap/oa"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://api.amazon.com/auth/o2/token"),
+            request_uri: String::from("https://api.amazon.com/user/profile"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+impl AmazonStrategy {
+    /// Login with Amazon region the customers' accounts live in. Defaults to North America.
+    pub fn region(mut self, region: AmazonRegion) -> Self {
+        let (auth_host, api_host) = match region {
+            AmazonRegion::NorthAmerica => ("www.amazon.com", "api.amazon.com"),
+            AmazonRegion::Europe => ("eu.account.amazon.com", "api.amazon.co.uk"),
+            AmazonRegion::FarEast => ("apac.account.amazon.com", "api.amazon.co.jp"),
+        };
+        self.auth_uri = format!("https://{}/ap/oa", auth_host);
+        self.token_uri = format!("https://{}/auth/o2/token", api_host);
+        self.request_uri = format!("https://{}/user/profile", api_host);
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct YahooStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for YahooStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://api.login.yahoo.com/oauth2/request_auth"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://api.login.yahoo.com/oauth2/get_token"),
+            request_uri: String::from("https://api.login.yahoo.com/openid/v1/userinfo"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+impl YahooStrategy {
+    const ISSUER: &'static str = "https://api.login.yahoo.com";
+}
+
+#[derive(Clone, Debug)]
+pub struct ZoomStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for ZoomStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://zoom.us/oauth/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://zoom.us/oauth/token"),
+            request_uri: String::from("https://api.zoom.us/v2/users/me"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+// Claims of the ID token in the token response. The token comes straight from the token endpoint over TLS,
+// so its signature is not checked again (OpenID Connect Core 3.1.3.7), but it must be issued by `issuer` for this
+// client. An `issuer` ending with a slash is matched as a prefix, i.e B2C issuers carry the tenant id.
+fn id_token_claims(
+    token: &PassportTokenResponse,
+    client_id: &str,
+    issuer: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let id_token = token
+        .extra_fields()
+        .get("id_token")
+        .and_then(|id_token| id_token.as_str())
+        .ok_or_else(|| anyhow!("Missing id_token in token response"))?;
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .ok_or_else(|| anyhow!("Malformed id_token"))?;
+    let claims: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+    let audience = match &claims["aud"] {
+        serde_json::Value::Array(audiences) => audiences.iter().any(|aud| aud == client_id),
+        aud => aud == client_id,
+    };
+    if !audience {
+        anyhow::bail!("id_token was not issued to this client")
+    }
+    let iss = claims["iss"].as_str().unwrap_or_default();
+    let expected = match issuer.ends_with('/') {
+        true => iss.starts_with(issuer),
+        false => iss == issuer,
+    };
+    if !expected {
+        anyhow::bail!("id_token from unexpected issuer {}", iss)
+    }
+    Ok(claims)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AzureB2cStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) tenant: String,
+    pub(crate) password_reset_redirect: String,
+}
+
+impl AzureB2cStrategy {
+    // B2C reports "Forgot your password?" on the sign-in page of a sign-up/sign-in user flow with this error.
+    const PASSWORD_RESET: &'static str = "AADB2C90118";
+
+    /// `tenant` is the B2C tenant name (`contoso` for `contoso.onmicrosoft.com`) and `policy` the user flow
+    /// or custom policy to run, i.e `B2C_1_signupsignin`. Include `openid` in the scopes, the profile is read from the ID token.
+    pub fn new(
+        tenant: &str,
+        policy: &str,
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let strategy = Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            scopes: scopes
+                .iter()
+                .map(ToString::to_string)
+                .map(Scope::new)
+                .collect(),
+            redirect_uri: redirect_uri.to_string(),
+            failure_redirect: failure_redirect.to_string(),
+            tenant: tenant.trim_end_matches(".onmicrosoft.com").to_lowercase(),
+            ..Default::default()
+        };
+        strategy.policy(policy)
+    }
+
+    /// The same app running another policy, i.e the password reset or profile edit user flow.
+    pub fn policy(&self, policy: &str) -> Self {
+        let policy_uri = format!(
+            "https://{tenant}.b2clogin.com/{tenant}.onmicrosoft.com/{policy}/oauth2/v2.0",
+            tenant = self.tenant,
+            policy = policy.to_lowercase()
+        );
+        let mut strategy = self.clone();
+        strategy.auth_uri = format!("{}/authorize", policy_uri);
+        strategy.token_uri = format!("{}/token", policy_uri);
+        strategy
+    }
+
+    /// Where the callback is redirected to when the user picked "Forgot your password?", typically a route
+    /// authenticating with the password reset policy strategy.
+    pub fn password_reset_redirect(mut self, url: &str) -> Self {
+        self.password_reset_redirect = url.to_string();
+        self
+    }
+
+    /// Whether the callback reports that the user asked to reset their password (`AADB2C90118`).
+    pub fn password_reset_requested(callback: &StateCode) -> bool {
+        callback
+            .params
+            .get("error_description")
+            .is_some_and(|description| description.starts_with(Self::PASSWORD_RESET))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LineStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) bot_prompt: String,
+}
+
+impl Default for LineStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://access.line.me/oauth2/v2.1/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://api.line.me/oauth2/v2.1/token"),
+            request_uri: String::from("https://api.line.me/v2/profile"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            bot_prompt: String::new(),
+        }
+    }
+}
+
+impl LineStrategy {
+    const ISSUER: &'static str = "https://access.line.me";
+
+    /// Offers to add the LINE Official Account linked to the channel as a friend, `normal` or `aggressive`.
+    pub fn bot_prompt(mut self, bot_prompt: &str) -> Self {
+        self.bot_prompt = bot_prompt.to_string();
+        self
+    }
+
+    // The email is only in the ID token, when the `openid email` scopes were granted.
+    fn line_profile(
+        &self,
+        mut profile: serde_json::Value,
+        token: &PassportTokenResponse,
+    ) -> anyhow::Result<serde_json::Value> {
+        let email = match token.extra_fields().get("id_token") {
+            Some(_) => id_token_claims(token, &self.client_id, Self::ISSUER)?["email"].clone(),
+            None => serde_json::Value::Null,
+        };
+        profile["id"] = profile["userId"].clone();
+        profile["name"] = profile["displayName"].clone();
+        profile["email"] = email;
+        profile["picture"] = profile["pictureUrl"].clone();
+        Ok(profile)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KakaoStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for KakaoStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://kauth.kakao.com/oauth/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://kauth.kakao.com/oauth/token"),
+            request_uri: String::from("https://kapi.kakao.com/v2/user/me"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+impl KakaoStrategy {
+    // The user details are nested in `kakao_account` and its `profile`.
+    fn kakao_profile(&self, mut profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        let account = profile["kakao_account"].clone();
+        profile["id"] = match &profile["id"] {
+            serde_json::Value::Number(id) => serde_json::json!(id.to_string()),
+            _ => anyhow::bail!("Missing id in Kakao profile"),
+        };
+        profile["name"] = account["profile"]["nickname"].clone();
+        profile["email"] = account["email"].clone();
+        profile["picture"] = account["profile"]["profile_image_url"].clone();
+        Ok(profile)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NaverStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for NaverStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_secret: String::new(),
+            auth_uri: String::from("https://nid.naver.com/oauth2.0/authorize"),
+            scopes: Vec::new(),
+            token_uri: String::from("https://nid.naver.com/oauth2.0/token"),
+            request_uri: String::from("https://openapi.naver.com/v1/nid/me"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+impl NaverStrategy {
+    // Naver wraps the user in a `response` envelope next to a `resultcode`, `00` meaning success.
+    fn naver_profile(&self, profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        if profile["resultcode"] != "00" {
+            anyhow::bail!(
+                "Naver profile request failed: {}",
+                profile["message"].as_str().unwrap_or("unknown error")
+            )
+        }
+        let mut user = profile["response"].clone();
+        user["picture"] = user["profile_image"].clone();
+        if user["name"].is_null() {
+            user["name"] = user["nickname"].clone();
+        }
+        Ok(user)
+    }
+}
+
+// Accepts a bare domain (i.e `tenant.eu.auth0.com`) as well as a full base url.
+fn base_url(domain: &str) -> String {
+    let domain = domain.trim_end_matches('/');
+    if domain.starts_with("https://") || domain.starts_with("http://") {
+        domain.to_string()
+    } else {
+        format!("https://{}", domain)
+    }
+}
+
+// Settings Keycloak, Okta and Auth0 share. Each of them embeds these, `provider_options!` gives them the builders and
+// `provider_options_overrides!` the matching `Strategy` methods.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProviderOptions {
+    par_uri: Option<String>,
+    dpop: bool,
+    client_auth: Option<ClientAuthMethod>,
+    certificate: Option<ClientCertificate>,
+}
+
+// `$par_path` is where the pushed authorization request endpoint lives, relative to the token endpoint's parent.
+macro_rules! provider_options {
+    ($($name:ty => $par_path:literal),*) => {
+        $(
+            impl $name {
+                /// Sends the authorization parameters through a pushed authorization request (RFC 9126), required where
+                /// the client is set to require PAR. Needs `generate_redirect_url_async`.
+                pub fn pushed_authorization_requests(mut self) -> Self {
+                    self.options.par_uri = Some(format!(
+                        "{}{}",
+                        self.token_uri.trim_end_matches("/token"),
+                        $par_path
+                    ));
+                    self
+                }
+
+                /// Binds the tokens to a per-login DPoP key (RFC 9449), see `PassPortBasicClient::get_profile`.
+                pub fn dpop(mut self) -> Self {
+                    self.options.dpop = true;
+                    self
+                }
+
+                /// How the client authenticates at the token endpoint, i.e `private_key_jwt` where shared secrets are
+                /// not allowed.
+                pub fn client_auth_method(mut self, method: ClientAuthMethod) -> Self {
+                    self.options.client_auth = Some(method);
+                    self
+                }
+
+                /// Presents a TLS client certificate to the token and profile endpoints (RFC 8705), pair it with
+                /// `ClientAuthMethod::TlsClientAuth` where the certificate is the client authentication.
+                pub fn client_certificate(mut self, certificate: ClientCertificate) -> Self {
+                    self.options.certificate = Some(certificate);
+                    self
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! provider_options_overrides {
+    ($device_path:literal) => {
+        fn pushed_authorization_url(&self) -> Option<String> {
+            self.options.par_uri.clone()
+        }
+
+        fn dpop(&self) -> bool {
+            self.options.dpop
+        }
+
+        fn client_auth_method(&self) -> ClientAuthMethod {
+            self.options
+                .client_auth
+                .clone()
+                .unwrap_or(ClientAuthMethod::ClientSecretBasic)
+        }
+
+        // The revocation endpoint sits next to the token endpoint.
+        fn revocation_url(&self) -> Option<String> {
+            self.token_uri
+                .strip_suffix("/token")
+                .map(|base| format!("{}/revoke", base))
+        }
+
+        fn client_certificate(&self) -> Option<ClientCertificate> {
+            self.options.certificate.clone()
+        }
+
+        fn device_authorization_url(&self) -> Option<String> {
+            self.token_uri
+                .strip_suffix("/token")
+                .map(|base| format!("{}{}", base, $device_path))
+        }
+    };
+}
+
+provider_options!(
+    KeycloakStrategy => "/ext/par/request",
+    OktaStrategy => "/par",
+    Auth0Strategy => "/par"
+);
+
+#[derive(Clone, Debug, Default)]
+pub struct KeycloakStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) options: ProviderOptions,
+}
+
+impl KeycloakStrategy {
+    /// `server` is the Keycloak base url, including the legacy `/auth` context path on servers older than Keycloak 17.
+    pub fn new(
+        server: &str,
+        realm: &str,
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let issuer = format!("{}/realms/{}", base_url(server), realm);
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_uri: format!("{}/protocol/openid-connect/auth", issuer),
+            scopes: scopes
+                .iter()
+                .map(ToString::to_string)
+                .map(Scope::new)
+                .collect(),
+            request_uri: format!("{}/protocol/openid-connect/userinfo", issuer),
+            token_uri: format!("{}/protocol/openid-connect/token", issuer),
+            redirect_uri: redirect_uri.to_string(),
+            failure_redirect: failure_redirect.to_string(),
+            options: ProviderOptions::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OktaStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) options: ProviderOptions,
+}
+
+impl OktaStrategy {
+    /// `domain` is the Okta org domain (i.e `dev-123456.okta.com`).
+    /// `authorization_server` is the id of a custom authorization server (i.e `default`), `None` uses the org authorization server.
+    pub fn new(
+        domain: &str,
+        authorization_server: Option<&str>,
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let issuer = match authorization_server {
+            Some(server) => format!("{}/oauth2/{}", base_url(domain), server),
+            None => format!("{}/oauth2", base_url(domain)),
+        };
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_uri: format!("{}/v1/authorize", issuer),
+            scopes: scopes
+                .iter()
+                .map(ToString::to_string)
+                .map(Scope::new)
+                .collect(),
+            request_uri: format!("{}/v1/userinfo", issuer),
+            token_uri: format!("{}/v1/token", issuer),
+            redirect_uri: redirect_uri.to_string(),
+            failure_redirect: failure_redirect.to_string(),
+            options: ProviderOptions::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Auth0Strategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) authorize_params: Vec<(String, String)>,
+    pub(crate) options: ProviderOptions,
+}
+
+impl Auth0Strategy {
+    /// `domain` is the tenant domain (i.e `tenant.eu.auth0.com`) or a custom domain.
+    pub fn new(
+        domain: &str,
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let domain = base_url(domain);
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_uri: format!("{}/authorize", domain),
+            scopes: scopes
+                .iter()
+                .map(ToString::to_string)
+                .map(Scope::new)
+                .collect(),
+            request_uri: format!("{}/userinfo", domain),
+            token_uri: format!("{}/oauth/token", domain),
+            redirect_uri: redirect_uri.to_string(),
+            failure_redirect: failure_redirect.to_string(),
+            authorize_params: Vec::new(),
+            options: ProviderOptions::default(),
+        }
+    }
+
+    /// API identifier the access token is issued for. Without it Auth0 issues an opaque token only good for `/userinfo`.
+    pub fn audience(self, audience: &str) -> Self {
+        self.with_authorize_param("audience", audience)
+    }
+
+    /// Logs the user in through an Auth0 Organization, either its id (`org_...`) or its name.
+    pub fn organization(self, organization: &str) -> Self {
+        self.with_authorize_param("organization", organization)
+    }
+
+    fn with_authorize_param(mut self, key: &str, value: &str) -> Self {
+        self.authorize_params
+            .push((key.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SalesforceDomain {
+    /// `login.salesforce.com`
+    Production,
+    /// `test.salesforce.com`
+    Sandbox,
+    /// The org's My Domain, i.e `acme.my.salesforce.com` or `acme--uat.sandbox.my.salesforce.com`.
+    MyDomain(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SalesforceStrategy {
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl SalesforceStrategy {
+    pub fn new(
+        domain: SalesforceDomain,
+        client_id: &str,
+        client_secret: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let domain = match domain {
+            SalesforceDomain::Production => String::from("https://login.salesforce.com"),
+            SalesforceDomain::Sandbox => String::from("https://test.salesforce.com"),
+            SalesforceDomain::MyDomain(domain) => base_url(&domain),
+        };
+        Self {
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            auth_uri: format!("{}/services/oauth2/authorize", domain),
+            scopes: scopes
+                .iter()
+                .map(ToString::to_string)
+                .map(Scope::new)
+                .collect(),
+            request_uri: format!("{}/services/oauth2/userinfo", domain),
+            token_uri: format!("{}/services/oauth2/token", domain),
+            redirect_uri: redirect_uri.to_string(),
+            failure_redirect: failure_redirect.to_string(),
+        }
+    }
+
+    // The `id` of the token response is the identity url of the user, on the instance the org lives on.
+    fn identity_uri(&self, token: &PassportTokenResponse) -> anyhow::Result<String> {
+        match token.extra_fields().get("id").and_then(|id| id.as_str()) {
+            Some(id) if id.starts_with("https://") => Ok(id.to_string()),
+            Some(id) => anyhow::bail!("Invalid Salesforce identity url: {}", id),
+            None => Ok(self.request_uri.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SteamStrategy {
+    pub(crate) api_key: String,
+    pub(crate) realm: String,
+    pub(crate) auth_uri: String,
+    pub(crate) request_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    // Response nonces already seen, with their issue time, to reject replayed callbacks.
+    pub(crate) nonces: Arc<Mutex<HashMap<String, i64>>>,
+}
+
+impl Default for SteamStrategy {
+    fn default() -> Self {
+        Self {
+            api_key: String::new(),
+            realm: String::new(),
+            auth_uri: String::from("https://steamcommunity.com/openid/login"),
+            request_uri: String::from(
+                "https://api.steampowered.com/ISteamUser/GetPlayerSummaries/v2/",
+            ),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            nonces: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl SteamStrategy {
+    const OPENID_NS: &'static str = "http://specs.openid.net/auth/2.0";
+    const IDENTIFIER_SELECT: &'static str = "http://specs.openid.net/auth/2.0/identifier_select";
+    const CLAIMED_ID_PREFIX: &'static str = "https://steamcommunity.com/openid/id/";
+    // How long a response nonce is accepted (and remembered) after Steam issued it.
+    const NONCE_MAX_AGE: i64 = 300;
+    // Clock skew tolerated on nonces dated ahead of us.
+    const NONCE_MAX_SKEW: i64 = 30;
+
+    /// `realm` is the origin Steam shows to the user (i.e `https://example.com`), the `redirect_uri` must live under it.
+    /// The `api_key` is the Steam Web API key used to fetch the player summary.
+    pub fn new(api_key: &str, realm: &str, redirect_uri: &str, failure_redirect: &str) -> Self {
+        let mut strategy = Self::default();
+        strategy.api_key.push_str(api_key);
+        strategy.realm.push_str(realm);
+        strategy.redirect_uri.push_str(redirect_uri);
+        strategy.failure_redirect.push_str(failure_redirect);
+        strategy
+    }
+
+    // Issue time of the nonce. Fails if it is too old to be remembered, dated in the future or was seen before.
+    fn check_nonce(&self, nonce: &str) -> anyhow::Result<i64> {
+        let issued = nonce
+            .get(..20)
+            .and_then(|timestamp| chrono::DateTime::parse_from_rfc3339(timestamp).ok())
+            .ok_or_else(|| anyhow!("Invalid OpenID response nonce"))?
+            .timestamp();
+        let now = chrono::Utc::now().timestamp();
+        if now - issued > Self::NONCE_MAX_AGE {
+            anyhow::bail!("Expired OpenID response nonce")
+        }
+        if issued - now > Self::NONCE_MAX_SKEW {
+            anyhow::bail!("OpenID response nonce dated in the future")
+        }
+        if self.nonces.lock().unwrap().contains_key(nonce) {
+            anyhow::bail!("Replayed OpenID response nonce")
+        }
+        Ok(issued)
+    }
+
+    // Marks a nonce Steam confirmed as used, only confirmed nonces are remembered.
+    fn consume_nonce(&self, nonce: &str, issued: i64) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut nonces = self.nonces.lock().unwrap();
+        nonces.retain(|_, issued| now - *issued <= Self::NONCE_MAX_AGE);
+        if nonces.insert(nonce.to_string(), issued).is_some() {
+            anyhow::bail!("Replayed OpenID response nonce")
+        }
+        Ok(())
+    }
+
+    async fn verify(&self, callback: &StateCode) -> anyhow::Result<PassportResponse> {
+        let params = &callback.params;
+        if callback.state.is_none()
+            || params.get("openid.mode").map(String::as_str) != Some("id_res")
+        {
+            return Ok(PassportResponse::FailureRedirect(self.failure_redirect()));
+        }
+        let param = |name: &str| {
+            params
+                .get(name)
+                .ok_or_else(|| anyhow!("Missing {} in OpenID callback", name))
+        };
+        if param("openid.op_endpoint")? != &self.auth_uri {
+            anyhow::bail!("Unexpected OpenID provider endpoint")
+        }
+        // return_to is covered by the signature, so it must be our redirect url carrying the same state.
+        let return_to = param("openid.return_to")?.parse::<Url>()?;
+        let redirect_uri = self.redirect_uri.parse::<Url>()?;
+        let returned_state = return_to
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, value)| value.into_owned());
+        if return_to.origin() != redirect_uri.origin()
+            || return_to.path() != redirect_uri.path()
+            || returned_state.as_ref() != callback.state.as_ref().map(|state| state.secret())
+        {
+            anyhow::bail!("OpenID return_to does not match the redirect url")
+        }
+        let steamid = param("openid.claimed_id")?
+            .strip_prefix(Self::CLAIMED_ID_PREFIX)
+            .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+            .ok_or_else(|| anyhow!("Invalid SteamID in OpenID claimed_id"))?
+            .to_string();
+        let nonce = param("openid.response_nonce")?;
+        let issued = self.check_nonce(nonce)?;
+
+        // Ask Steam to verify the signature, the assertion is only trusted once it answers `is_valid:true`.
+        let mut verification = params
+            .iter()
+            .filter(|(key, _)| key.starts_with("openid."))
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+        verification.retain(|(key, _)| *key != "openid.mode");
+        verification.push(("openid.mode", "check_authentication"));
+        let body = reqwest::Client::new()
+            .post(&self.auth_uri)
+            .form(&verification)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if !body.lines().any(|line| line.trim() == "is_valid:true") {
+            anyhow::bail!("Steam rejected the OpenID assertion")
+        }
+        self.consume_nonce(nonce, issued)?;
+
+        let response = reqwest::Client::new()
+            .get(&self.request_uri)
+            .query(&[
+                ("key", self.api_key.as_str()),
+                ("steamids", steamid.as_str()),
+            ])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        let summary = response.json::<serde_json::Value>().await?;
+        let mut profile = summary["response"]["players"][0].clone();
+        if !profile.is_object() {
+            anyhow::bail!("Steam returned no player summary for {}", steamid)
+        }
+        profile["steamid"] = serde_json::json!(steamid);
+        Ok(PassportResponse::Profile(profile))
+    }
+}
+
+#[async_trait]
+impl Strategy for SteamStrategy {
+    fn request_uri(&self) -> String {
+        self.request_uri.clone()
+    }
+    fn scopes(&self) -> Vec<Scope> {
+        Vec::new()
+    }
+    fn client_id(&self) -> String {
+        self.realm.clone()
+    }
+
+    fn client_secret(&self) -> String {
+        self.api_key.clone()
+    }
+
+    fn auth_url(&self) -> String {
+        self.auth_uri.clone()
+    }
+
+    fn redirect_url(&self) -> String {
+        self.redirect_uri.clone()
+    }
+
+    fn failure_redirect(&self) -> Url {
+        match self.failure_redirect.parse::<reqwest::Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Url".bold().red(), err),
+        }
+    }
+
+    fn token_url(&self) -> Option<TokenUrl> {
+        None
+    }
+
+    fn authorize_redirect(&self, state: &CsrfToken) -> Option<Url> {
+        // The state travels in return_to, Steam sends it back untouched alongside the openid.* fields.
+        let mut return_to = match self.redirect_uri.parse::<Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Redirect URL: ".bold().red(), err),
+        };
+        return_to
+            .query_pairs_mut()
+            .append_pair("state", state.secret());
+        let mut url = match self.auth_uri.parse::<Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Authentication URL: ".bold().red(), err),
+        };
+        url.query_pairs_mut()
+            .append_pair("openid.ns", Self::OPENID_NS)
+            .append_pair("openid.mode", "checkid_setup")
+            .append_pair("openid.return_to", return_to.as_str())
+            .append_pair("openid.realm", &self.realm)
+            .append_pair("openid.identity", Self::IDENTIFIER_SELECT)
+            .append_pair("openid.claimed_id", Self::IDENTIFIER_SELECT);
+        Some(url)
+    }
+
+    async fn verify_callback(
+        &self,
+        callback: &StateCode,
+    ) -> Option<anyhow::Result<PassportResponse>> {
+        Some(self.verify(callback).await)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WechatStrategy {
+    pub(crate) app_id: String,
+    pub(crate) app_secret: String,
+    pub(crate) auth_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) request_uri: String,
+    pub(crate) token_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+}
+
+impl Default for WechatStrategy {
+    fn default() -> Self {
+        Self {
+            app_id: String::new(),
+            app_secret: String::new(),
+            auth_uri: String::from("https://open.weixin.qq.com/connect/qrconnect"),
+            scopes: vec![Scope::new(String::from("snsapi_login"))],
+            token_uri: String::from("https://api.weixin.qq.com/sns/oauth2/access_token"),
+            request_uri: String::from("https://api.weixin.qq.com/sns/userinfo"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+        }
+    }
+}
+
+impl WechatStrategy {
+    /// Website application (QR code login) registered on the WeChat Open Platform.
+    pub fn new(app_id: &str, app_secret: &str, redirect_uri: &str, failure_redirect: &str) -> Self {
+        let mut strategy = Self::default();
+        strategy.app_id.push_str(app_id);
+        strategy.app_secret.push_str(app_secret);
+        strategy.redirect_uri.push_str(redirect_uri);
+        strategy.failure_redirect.push_str(failure_redirect);
+        strategy
+    }
+
+    // WeChat answers errors with `200 OK` and an `errcode`.
+    async fn get(&self, url: &str, query: &[(&str, &str)]) -> anyhow::Result<serde_json::Value> {
+        let response = reqwest::Client::new().get(url).query(query).send().await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        let json = response.json::<serde_json::Value>().await?;
+        if let Some(errcode) = json["errcode"].as_i64().filter(|errcode| *errcode != 0) {
+            anyhow::bail!(
+                "WeChat error {}: {}",
+                errcode,
+                json["errmsg"].as_str().unwrap_or_default()
+            )
+        }
+        Ok(json)
+    }
+
+    // The token request takes `appid` and `secret` as query parameters, and answers with the `openid` needed by `/sns/userinfo`.
+    async fn exchange(&self, callback: &StateCode) -> anyhow::Result<PassportResponse> {
+        let code = match (callback.state.as_ref(), callback.code.as_ref()) {
+            (Some(_), Some(code)) => code,
+            _ => return Ok(PassportResponse::FailureRedirect(self.failure_redirect())),
+        };
+        let token = self
+            .get(
+                &self.token_uri,
+                &[
+                    ("appid", self.app_id.as_str()),
+                    ("secret", self.app_secret.as_str()),
+                    ("code", code.as_str()),
+                    ("grant_type", "authorization_code"),
+                ],
+            )
+            .await?;
+        let (access_token, openid) =
+            match (token["access_token"].as_str(), token["openid"].as_str()) {
+                (Some(access_token), Some(openid)) => (access_token, openid),
+                _ => anyhow::bail!("WeChat token response without access_token or openid"),
+            };
+        let mut profile = self
+            .get(
+                &self.request_uri,
+                &[("access_token", access_token), ("openid", openid)],
+            )
+            .await?;
+        if profile["unionid"].is_null() {
+            profile["unionid"] = token["unionid"].clone();
+        }
+        profile["access_token"] = serde_json::json!(access_token);
+        profile["refresh_token"] = token["refresh_token"].clone();
+        Ok(PassportResponse::Profile(profile))
+    }
+}
+
+#[async_trait]
+impl Strategy for WechatStrategy {
+    fn request_uri(&self) -> String {
+        self.request_uri.clone()
+    }
+    fn scopes(&self) -> Vec<Scope> {
+        self.scopes.clone()
+    }
+    fn client_id(&self) -> String {
+        self.app_id.clone()
+    }
+
+    fn client_secret(&self) -> String {
+        self.app_secret.clone()
+    }
+
+    fn auth_url(&self) -> String {
+        self.auth_uri.clone()
+    }
+
+    fn redirect_url(&self) -> String {
+        self.redirect_uri.clone()
+    }
+
+    fn failure_redirect(&self) -> Url {
+        match self.failure_redirect.parse::<reqwest::Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Url".bold().red(), err),
+        }
+    }
+
+    fn token_url(&self) -> Option<TokenUrl> {
+        None
+    }
+
+    // `appid` instead of `client_id`, and the `#wechat_redirect` fragment WeChat requires.
+    fn authorize_redirect(&self, state: &CsrfToken) -> Option<Url> {
+        let mut url = match self.auth_uri.parse::<Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Authentication URL: ".bold().red(), err),
+        };
+        let scopes = self
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(",");
+        url.query_pairs_mut()
+            .append_pair("appid", &self.app_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("response_type", "code")
+            .append_pair("scope", &scopes)
+            .append_pair("state", state.secret());
+        url.set_fragment(Some("wechat_redirect"));
+        Some(url)
+    }
+
+    async fn verify_callback(
+        &self,
+        callback: &StateCode,
+    ) -> Option<anyhow::Result<PassportResponse>> {
+        Some(self.exchange(callback).await)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TelegramStrategy {
+    pub(crate) bot_token: String,
+    pub(crate) auth_uri: String,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    pub(crate) max_age: i64,
+}
+
+impl Default for TelegramStrategy {
+    fn default() -> Self {
+        Self {
+            bot_token: String::new(),
+            auth_uri: String::from("https://oauth.telegram.org/auth"),
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            max_age: 300,
+        }
+    }
+}
+
+impl TelegramStrategy {
+    /// The `bot_token` is the token issued by @BotFather for the bot linked to your domain.
+    /// Point the login widget's `data-auth-url` at the `redirect_uri`, Telegram then sends the signed user data there.
+    pub fn new(bot_token: &str, redirect_uri: &str, failure_redirect: &str) -> Self {
+        let mut strategy = Self::default();
+        strategy.bot_token.push_str(bot_token);
+        strategy.redirect_uri.push_str(redirect_uri);
+        strategy.failure_redirect.push_str(failure_redirect);
+        strategy
+    }
+
+    /// How many seconds after `auth_date` the login data is still accepted. Defaults to five minutes, signed login data
+    /// can be replayed for as long as it is accepted.
+    pub fn max_age(mut self, seconds: i64) -> Self {
+        self.max_age = seconds;
+        self
+    }
+
+    fn verify(&self, callback: &StateCode) -> anyhow::Result<PassportResponse> {
+        let params = &callback.params;
+        let hash = match params.get("hash") {
+            Some(hash) => hex::decode(hash).map_err(|_| anyhow!("Invalid Telegram login hash"))?,
+            None => return Ok(PassportResponse::FailureRedirect(self.failure_redirect())),
+        };
+        // The data-check-string is every received field but the hash, sorted and joined with line feeds.
+        let mut fields = params
+            .iter()
+            .filter(|(key, _)| key.as_str() != "hash")
+            .collect::<Vec<_>>();
+        fields.sort();
+        let check = fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(self.bot_token.as_bytes()))?;
+        mac.update(check.as_bytes());
+        mac.verify_slice(&hash)
+            .map_err(|_| anyhow!("Telegram login data failed hash verification"))?;
+
+        let auth_date = params
+            .get("auth_date")
+            .and_then(|date| date.parse::<i64>().ok())
+            .ok_or_else(|| anyhow!("Missing auth_date in Telegram login data"))?;
+        if chrono::Utc::now().timestamp() - auth_date > self.max_age {
+            anyhow::bail!("Telegram login data is outdated")
+        }
+
+        let mut profile = serde_json::json!(params);
+        profile.as_object_mut().unwrap().remove("hash");
+        for field in ["id", "auth_date"] {
+            if let Some(value) = params
+                .get(field)
+                .and_then(|value| value.parse::<i64>().ok())
+            {
+                profile[field] = serde_json::json!(value);
+            }
+        }
+        Ok(PassportResponse::Profile(profile))
+    }
+}
+
+#[async_trait]
+impl Strategy for TelegramStrategy {
+    fn request_uri(&self) -> String {
+        String::new()
+    }
+    fn scopes(&self) -> Vec<Scope> {
+        Vec::new()
+    }
+    fn client_id(&self) -> String {
+        // The bot id is the part of the token before the colon.
+        self.bot_token
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    fn client_secret(&self) -> String {
+        self.bot_token.clone()
+    }
+
+    fn auth_url(&self) -> String {
+        self.auth_uri.clone()
+    }
+
+    fn redirect_url(&self) -> String {
+        self.redirect_uri.clone()
+    }
+
+    fn failure_redirect(&self) -> Url {
+        match self.failure_redirect.parse::<reqwest::Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Url".bold().red(), err),
+        }
+    }
+
+    fn token_url(&self) -> Option<TokenUrl> {
+        None
+    }
+
+    fn authorize_redirect(&self, _state: &CsrfToken) -> Option<Url> {
+        let redirect_uri = match self.redirect_uri.parse::<Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Redirect URL: ".bold().red(), err),
+        };
+        let mut url = match self.auth_uri.parse::<Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Authentication URL: ".bold().red(), err),
+        };
+        url.query_pairs_mut()
+            .append_pair("bot_id", &self.client_id())
+            .append_pair("origin", &redirect_uri.origin().ascii_serialization())
+            .append_pair("request_access", "write")
+            .append_pair("return_to", redirect_uri.as_str());
+        Some(url)
+    }
+
+    async fn verify_callback(
+        &self,
+        callback: &StateCode,
+    ) -> Option<anyhow::Result<PassportResponse>> {
+        Some(self.verify(callback))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AtprotoStrategy {
+    // Url of the client metadata document, it stands in for a registered client id.
+    pub(crate) client_id: String,
+    pub(crate) client_name: String,
+    pub(crate) client_uri: String,
+    pub(crate) scopes: Vec<Scope>,
+    pub(crate) redirect_uri: String,
+    pub(crate) failure_redirect: String,
+    // Service asked when the user gives no handle, and the fallback handle resolver.
+    pub(crate) entryway: String,
+    pub(crate) plc_directory: String,
+    // Authorization requests in flight, by state.
+    pub(crate) pending: Arc<Mutex<HashMap<String, AtprotoAuthorization>>>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct AtprotoAuthorization {
+    verifier: String,
+    dpop: DpopKey,
+    issuer: String,
+    token_endpoint: String,
+    // Account the user asked for, `None` when they started from a server.
+    did: Option<String>,
+    created: i64,
+}
+
+impl Default for AtprotoStrategy {
+    fn default() -> Self {
+        Self {
+            client_id: String::new(),
+            client_name: String::new(),
+            client_uri: String::new(),
+            scopes: vec![Scope::new(String::from("atproto"))],
+            redirect_uri: String::new(),
+            failure_redirect: String::new(),
+            entryway: String::from("https://bsky.social"),
+            plc_directory: String::from("https://plc.directory"),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl AtprotoStrategy {
+    // How long an authorization request is remembered, the pushed request itself expires sooner.
+    const PENDING_MAX_AGE: i64 = 600;
+
+    /// `client_id` is the https url the client metadata document (see `client_metadata`) is served at. The `atproto`
+    /// scope is always requested, i.e add `transition:generic` for Bluesky app access.
+    pub fn new(
+        client_id: &str,
+        scopes: Vec<&str>,
+        redirect_uri: &str,
+        failure_redirect: &str,
+    ) -> Self {
+        let mut strategy = Self::default();
+        strategy.client_id.push_str(client_id);
+        strategy.redirect_uri.push_str(redirect_uri);
+        strategy.failure_redirect.push_str(failure_redirect);
+        strategy.scopes.extend(
+            scopes
+                .iter()
+                .filter(|scope| **scope != "atproto")
+                .map(ToString::to_string)
+                .map(Scope::new),
+        );
+        strategy
+    }
+
+    pub fn client_name(mut self, client_name: &str) -> Self {
+        self.client_name = client_name.to_string();
+        self
+    }
+
+    pub fn client_uri(mut self, client_uri: &str) -> Self {
+        self.client_uri = client_uri.to_string();
+        self
+    }
+
+    /// Server users without a handle sign in at, `https://bsky.social` by default. It also resolves handles that
+    /// cannot be resolved over https (DNS only handles).
+    pub fn entryway(mut self, entryway: &str) -> Self {
+        self.entryway = base_url(entryway);
+        self
+    }
+
+    /// Directory `did:plc` identities are resolved with, `https://plc.directory` by default.
+    pub fn plc_directory(mut self, plc_directory: &str) -> Self {
+        self.plc_directory = plc_directory.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Client metadata document to serve at the `client_id` url.
+    pub fn client_metadata(&self) -> serde_json::Value {
+        let mut metadata = serde_json::json!({
+            "client_id": self.client_id,
+            "application_type": "web",
+            "grant_types": ["authorization_code", "refresh_token"],
+            "response_types": ["code"],
+            "redirect_uris": [self.redirect_uri],
+            "scope": self.scope(),
+            "token_endpoint_auth_method": "none",
+            "dpop_bound_access_tokens": true,
+        });
+        if !self.client_name.is_empty() {
+            metadata["client_name"] = serde_json::json!(self.client_name);
+        }
+        if !self.client_uri.is_empty() {
+            metadata["client_uri"] = serde_json::json!(self.client_uri);
+        }
+        metadata
+    }
+
+    fn scope(&self) -> String {
+        self.scopes
+            .iter()
+            .map(|scope| scope.as_str())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Servers must be https, plain http is only accepted on the loopback host (i.e a local test PDS).
+    fn endpoint(url: &str) -> anyhow::Result<Url> {
+        let url = url.parse::<Url>()?;
+        let loopback = matches!(
+            url.host_str(),
+            Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+        );
+        if url.scheme() != "https" && !(url.scheme() == "http" && loopback) {
+            anyhow::bail!("AT Protocol servers must be https: {}", url)
+        }
+        Ok(url)
+    }
+
+    async fn get_json(url: &str) -> anyhow::Result<serde_json::Value> {
+        let response = reqwest::Client::new()
+            .get(Self::endpoint(url)?)
+            .header(reqwest::header::ACCEPT, "application/json")
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        Ok(response.json::<serde_json::Value>().await?)
+    }
+
+    // https resolution first, then the entryway's resolveHandle which also covers DNS TXT records.
+    async fn resolve_handle(&self, handle: &str) -> anyhow::Result<String> {
+        let well_known = async {
+            let response = reqwest::Client::new()
+                .get(Self::endpoint(&format!(
+                    "https://{}/.well-known/atproto-did",
+                    handle
+                ))?)
+                .send()
+                .await?
+                .error_for_status()?;
+            anyhow::Ok(response.text().await?.trim().to_string())
+        };
+        if let Ok(did) = well_known.await {
+            if did.starts_with("did:") {
+                return Ok(did);
+            }
+        }
+        let mut url = Self::endpoint(&format!(
+            "{}/xrpc/com.atproto.identity.resolveHandle",
+            self.entryway
+        ))?;
+        url.query_pairs_mut().append_pair("handle", handle);
+        Self::get_json(url.as_str())
+            .await?
+            .get("did")
+            .and_then(|did| did.as_str())
+            .map(ToString::to_string)
+            .ok_or_else(|| anyhow!("Could not resolve handle {}", handle))
+    }
+
+    async fn resolve_did(&self, did: &str) -> anyhow::Result<serde_json::Value> {
+        let document = if did.starts_with("did:plc:") {
+            Self::get_json(&format!("{}/{}", self.plc_directory, did)).await?
+        } else if let Some(host) = did.strip_prefix("did:web:") {
+            let host = host.replace("%3A", ":");
+            let scheme = match host.split(':').next() {
+                Some("localhost") | Some("127.0.0.1") => "http",
+                _ => "https",
+            };
+            Self::get_json(&format!("{}://{}/.well-known/did.json", scheme, host)).await?
+        } else {
+            anyhow::bail!("Unsupported DID method: {}", did)
+        };
+        if document["id"] != did {
+            anyhow::bail!("DID document does not belong to {}", did)
+        }
+        Ok(document)
+    }
+
+    fn pds(document: &serde_json::Value) -> anyhow::Result<String> {
+        document["service"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|service| {
+                service["id"]
+                    .as_str()
+                    .is_some_and(|id| id.ends_with("#atproto_pds"))
+                    && service["type"] == "AtprotoPersonalDataServer"
+            })
+            .and_then(|service| service["serviceEndpoint"].as_str())
+            .map(|endpoint| endpoint.trim_end_matches('/').to_string())
+            .ok_or_else(|| anyhow!("DID document without a PDS"))
+    }
+
+    fn handle(document: &serde_json::Value) -> Option<String> {
+        document["alsoKnownAs"]
+            .as_array()?
+            .iter()
+            .find_map(|aka| aka.as_str()?.strip_prefix("at://"))
+            .map(ToString::to_string)
+    }
+
+    // Authorization server metadata of the server behind a PDS. The entryway may be an authorization server itself.
+    async fn authorization_server(&self, server: &str) -> anyhow::Result<serde_json::Value> {
+        let resource =
+            Self::get_json(&format!("{}/.well-known/oauth-protected-resource", server)).await;
+        let issuer = match resource {
+            Ok(resource) => resource["authorization_servers"][0]
+                .as_str()
+                .ok_or_else(|| anyhow!("PDS {} names no authorization server", server))?
+                .trim_end_matches('/')
+                .to_string(),
+            Err(_) => server.to_string(),
+        };
+        let metadata = Self::get_json(&format!(
+            "{}/.well-known/oauth-authorization-server",
+            issuer
+        ))
+        .await?;
+        if metadata["issuer"]
+            .as_str()
+            .map(|iss| iss.trim_end_matches('/'))
+            != Some(&issuer)
+        {
+            anyhow::bail!(
+                "Authorization server metadata does not belong to {}",
+                issuer
+            )
+        }
+        Ok(metadata)
+    }
+
+    // Resolves the account or server the user typed in, then pushes the authorization request to its authorization
+    // server and redirects with only the returned `request_uri`.
+    async fn authorize(&self, state: &CsrfToken, login_hint: Option<&str>) -> anyhow::Result<Url> {
+        let login_hint = login_hint
+            .map(|hint| hint.trim().trim_start_matches('@'))
+            .filter(|hint| !hint.is_empty());
+        let (did, server) = match login_hint {
+            Some(hint) if hint.starts_with("https://") || hint.starts_with("http://") => {
+                (None, hint.trim_end_matches('/').to_string())
+            }
+            Some(hint) => {
+                let did = if hint.starts_with("did:") {
+                    hint.to_string()
+                } else {
+                    self.resolve_handle(&hint.to_lowercase()).await?
+                };
+                let document = self.resolve_did(&did).await?;
+                (Some(did), Self::pds(&document)?)
+            }
+            None => (None, self.entryway.clone()),
+        };
+        let metadata = self.authorization_server(&server).await?;
+        let endpoint = |name: &str| {
+            metadata[name]
+                .as_str()
+                .map(ToString::to_string)
+                .ok_or_else(|| anyhow!("Authorization server metadata without {}", name))
+        };
+        let par_endpoint = endpoint("pushed_authorization_request_endpoint")?;
+        if let Some(algs) = metadata["dpop_signing_alg_values_supported"].as_array() {
+            if !algs.iter().any(|alg| alg == "ES256") {
+                anyhow::bail!("Authorization server does not accept ES256 DPoP proofs")
+            }
+        }
+
+        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+        let dpop = DpopKey::generate();
+        let scope = self.scope();
+        let mut params = vec![
+            ("response_type", "code"),
+            ("client_id", self.client_id.as_str()),
+            ("redirect_uri", self.redirect_uri.as_str()),
+            ("scope", scope.as_str()),
+            ("state", state.secret().as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ];
+        if let Some(hint) = login_hint.filter(|_| did.is_some()) {
+            params.push(("login_hint", hint));
+        }
+        let request = reqwest::Client::new()
+            .post(Self::endpoint(&par_endpoint)?)
+            .form(&params)
+            .build()?;
+        let response = dpop.send(request, None).await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        let pushed = response.json::<serde_json::Value>().await?;
+        let request_uri = pushed["request_uri"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Pushed authorization response without request_uri"))?;
+
+        let now = chrono::Utc::now().timestamp();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, authorization| now - authorization.created <= Self::PENDING_MAX_AGE);
+        pending.insert(
+            state.secret().to_string(),
+            AtprotoAuthorization {
+                verifier: verifier.secret().to_string(),
+                dpop,
+                issuer: metadata["issuer"].as_str().unwrap().to_string(),
+                token_endpoint: endpoint("token_endpoint")?,
+                did,
+                created: now,
+            },
+        );
+        let mut url = Self::endpoint(&endpoint("authorization_endpoint")?)?;
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("request_uri", request_uri);
+        Ok(url)
+    }
+
+    async fn exchange(&self, callback: &StateCode) -> anyhow::Result<PassportResponse> {
+        let authorization = callback
+            .state
+            .as_ref()
+            .and_then(|state| self.pending.lock().unwrap().remove(state.secret()));
+        let (code, authorization) = match (callback.code.as_ref(), authorization) {
+            (Some(code), Some(authorization)) => (code, authorization),
+            _ => return Ok(PassportResponse::FailureRedirect(self.failure_redirect())),
+        };
+        // RFC 9207, the code must come from the server the request was pushed to.
+        if callback.params.get("iss") != Some(&authorization.issuer) {
+            anyhow::bail!("Authorization response from an unexpected issuer")
+        }
+        let dpop = &authorization.dpop;
+        let request = reqwest::Client::new()
+            .post(Self::endpoint(&authorization.token_endpoint)?)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code.as_str()),
+                ("redirect_uri", self.redirect_uri.as_str()),
+                ("client_id", self.client_id.as_str()),
+                ("code_verifier", authorization.verifier.as_str()),
+            ])
+            .build()?;
+        let response = dpop.send(request, None).await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        let token = response.json::<serde_json::Value>().await?;
+        let access_token = token["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Token response without access_token"))?;
+        if !token["token_type"]
+            .as_str()
+            .is_some_and(|typ| typ.eq_ignore_ascii_case("DPoP"))
+        {
+            anyhow::bail!("Token response is not DPoP bound")
+        }
+        if !token["scope"]
+            .as_str()
+            .is_some_and(|scope| scope.split(' ').any(|scope| scope == "atproto"))
+        {
+            anyhow::bail!("Token response without the atproto scope")
+        }
+        let did = token["sub"]
+            .as_str()
+            .filter(|sub| sub.starts_with("did:"))
+            .ok_or_else(|| anyhow!("Token response without a DID subject"))?;
+        if authorization
+            .did
+            .as_deref()
+            .is_some_and(|expected| expected != did)
+        {
+            anyhow::bail!("Signed in account {} is not the one requested", did)
+        }
+        // The issuer must be the authority of the account's PDS, otherwise any server could claim any DID.
+        let document = self.resolve_did(did).await?;
+        let pds = Self::pds(&document)?;
+        let metadata = self.authorization_server(&pds).await?;
+        if metadata["issuer"] != authorization.issuer.as_str() {
+            anyhow::bail!(
+                "{} is not the authorization server of {}",
+                authorization.issuer,
+                did
+            )
+        }
+
+        let request = reqwest::Client::new()
+            .get(Self::endpoint(&format!(
+                "{}/xrpc/com.atproto.server.getSession",
+                pds
+            ))?)
+            .build()?;
+        let response = dpop.send(request, Some(access_token)).await?;
+        if !response.status().is_success() {
+            anyhow::bail!(response.text().await?)
+        }
+        let mut profile = response.json::<serde_json::Value>().await?;
+        if profile["did"] != did {
+            anyhow::bail!("PDS session does not belong to {}", did)
+        }
+        if profile["handle"].is_null() {
+            profile["handle"] = serde_json::json!(Self::handle(&document));
+        }
+        profile["pds"] = serde_json::json!(pds);
+        profile["issuer"] = serde_json::json!(authorization.issuer);
+        profile["access_token"] = serde_json::json!(access_token);
+        profile["refresh_token"] = token["refresh_token"].clone();
+        // The tokens are bound to this key, it has to be kept with them.
+        profile["dpop_key"] = dpop.to_jwk();
+        Ok(PassportResponse::Profile(profile))
+    }
+}
+
+#[async_trait]
+impl Strategy for AtprotoStrategy {
+    fn request_uri(&self) -> String {
+        String::new()
+    }
+    fn scopes(&self) -> Vec<Scope> {
+        self.scopes.clone()
+    }
+    fn client_id(&self) -> String {
+        self.client_id.clone()
+    }
+
+    fn client_secret(&self) -> String {
+        String::new()
+    }
+
+    fn auth_url(&self) -> String {
+        format!("{}/oauth/authorize", self.entryway)
+    }
+
+    fn redirect_url(&self) -> String {
+        self.redirect_uri.clone()
+    }
+
+    fn failure_redirect(&self) -> Url {
+        match self.failure_redirect.parse::<reqwest::Url>() {
+            Ok(url) => url,
+            Err(err) => panic!("{}{:?}", "Invalid Url".bold().red(), err),
+        }
+    }
+
+    fn token_url(&self) -> Option<TokenUrl> {
+        None
+    }
+
+    async fn resolve_redirect(
+        &self,
+        state: &CsrfToken,
+        login_hint: Option<&str>,
+    ) -> Option<anyhow::Result<Url>> {
+        Some(self.authorize(state, login_hint).await)
+    }
+
+    async fn verify_callback(
+        &self,
+        callback: &StateCode,
+    ) -> Option<anyhow::Result<PassportResponse>> {
+        Some(self.exchange(callback).await)
+    }
+}
+
+#[async_trait]
+pub trait Strategy: Debug + Send + Sync {
+    fn redirect_url(&self) -> String;
+    fn request_uri(&self) -> String;
+    fn scopes(&self) -> Vec<Scope>;
+    fn client_id(&self) -> String;
+    fn client_secret(&self) -> String;
+    fn auth_url(&self) -> String;
+    fn token_url(&self) -> Option<TokenUrl>;
+    fn failure_redirect(&self) -> Url;
+
+    /// Redirect for strategies that are not standard OAuth 2.0 providers (OpenID 2.0, WeChat). `None` keeps the authorization code redirect.
+    fn authorize_redirect(&self, _state: &CsrfToken) -> Option<Url> {
+        None
+    }
+
+    /// Redirect that needs network calls first, i.e resolving the user's server and pushing the authorization request
+    /// (AT Protocol). `login_hint` is the account the user typed in. `None` keeps `authorize_redirect`.
+    async fn resolve_redirect(
+        &self,
+        _state: &CsrfToken,
+        _login_hint: Option<&str>,
+    ) -> Option<anyhow::Result<Url>> {
+        None
+    }
+
+    /// Callback verification for strategies that are not standard OAuth 2.0 providers. `None` keeps the authorization code exchange.
+    async fn verify_callback(
+        &self,
+        _callback: &StateCode,
+    ) -> Option<anyhow::Result<PassportResponse>> {
+        None
+    }
+
+    /// Where a callback without a code (cancelled authorization, provider error) is redirected to.
+    fn failure_redirect_for(&self, _callback: &StateCode) -> Url {
+        self.failure_redirect()
+    }
+
+    /// Checks the callback of the authorization code flow before the code is exchanged, i.e a signature over the query.
+    fn check_callback(&self, _callback: &StateCode) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the authorization code flow sends a PKCE challenge. Some providers reject it for confidential clients.
+    fn pkce(&self) -> bool {
+        true
+    }
+
+    /// Device authorization endpoint (RFC 8628) used by `start_device_flow`. `None` if the provider has no device flow.
+    fn device_authorization_url(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether tokens are bound to a DPoP key (RFC 9449) generated for each login. The proofs go with the token, refresh and
+    /// profile requests, and the private key is returned in the profile as `dpop_key`.
+    fn dpop(&self) -> bool {
+        false
+    }
+
+    /// How the client id and secret are sent to the token endpoint.
+    fn auth_type(&self) -> AuthType {
+        AuthType::BasicAuth
+    }
+
+    /// How the client authenticates at the token, refresh and revocation endpoints. Defaults to the secret, sent the
+    /// way `auth_type` says.
+    fn client_auth_method(&self) -> ClientAuthMethod {
+        match self.auth_type() {
+            AuthType::RequestBody => ClientAuthMethod::ClientSecretPost,
+            _ => ClientAuthMethod::ClientSecretBasic,
+        }
+    }
+
+    /// TLS client certificate presented on the token, refresh and profile requests (RFC 8705). JWT access tokens issued
+    /// with it must carry its thumbprint in `cnf.x5t#S256`.
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        None
+    }
+
+    /// Token revocation endpoint (RFC 7009) used by `revoke_token`.
+    fn revocation_url(&self) -> Option<String> {
+        None
+    }
+
+    /// `pushed_authorization_request_endpoint` the authorization parameters are posted to (RFC 9126) by
+    /// `generate_redirect_url_async`, the redirect then only carries `client_id` and `request_uri`. `None` sends them in the query.
+    fn pushed_authorization_url(&self) -> Option<String> {
+        None
+    }
+
+    /// Provider specific parameters added to the authorization url, i.e `token_access_type=offline`.
+    fn authorize_params(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Provider specific parameters added to the token request, i.e Naver wants the `state` back.
+    fn token_params(&self, _callback: &StateCode) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Extra headers sent with the token request.
+    fn token_headers(&self) -> HeaderMap {
+        HeaderMap::new()
+    }
+
+    /// HTTP method of the profile request. Some providers (i.e Dropbox) only answer `POST`.
+    fn profile_method(&self) -> Method {
+        Method::GET
+    }
+
+    /// Extra headers sent with the profile request. They replace the default `Authorization` and `User-Agent` headers of the same name.
+    fn profile_headers(&self) -> HeaderMap {
+        HeaderMap::new()
+    }
+
+    /// Extra query parameters sent with the profile request.
+    fn profile_params(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// Validates and normalizes the fetched profile before the tokens are added to it.
+    fn map_profile(&self, profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        Ok(profile)
+    }
+
+    /// Profile built from the token response for providers without a cheap profile endpoint. `None` fetches the profile from `profile_uri`.
+    fn token_profile(
+        &self,
+        _token: &PassportTokenResponse,
+    ) -> Option<anyhow::Result<serde_json::Value>> {
+        None
+    }
+
+    /// Url the profile is fetched from. Defaults to the fixed `request_uri`, some providers (i.e Salesforce) name it in the token response.
+    fn profile_uri(&self, _token: &PassportTokenResponse) -> anyhow::Result<String> {
+        Ok(self.request_uri())
+    }
+
+    /// Further provider calls made with the token once the profile is fetched, i.e the sites a user granted.
+    async fn extend_profile(
+        &self,
+        profile: serde_json::Value,
+        _token: &PassportTokenResponse,
+    ) -> anyhow::Result<serde_json::Value> {
+        Ok(profile)
+    }
+}
+
+macro_rules! new_strategy {
+    ($($name:ty),*) => {
+        $(
+            impl $name {
+                pub fn new(
+                    client_id: &str,
+                    client_secret: &str,
+                    scopes: Vec<&str>,
+                    redirect_uri: &str,
+                    failure_redirect: &str
+                ) -> Self {
+                    let mut strategy = Self::default();
+                    strategy.client_id.push_str(client_id);
+                    strategy.client_secret.push_str(client_secret);
+                    strategy.redirect_uri.push_str(redirect_uri);
+                    strategy.failure_redirect.push_str(failure_redirect);
+                    strategy
+                        .scopes
+                        .extend(scopes.iter().map(ToString::to_string).map(Scope::new));
+                    strategy
+                }
+            }
+        )*
+    };
+}
+
+// A strategy can be followed by a block of trait methods overriding the defaults, i.e `LinkedInStrategy { fn pkce(&self) -> bool { false } }`.
+macro_rules! strategy {
+    ($($name:ty $({ $($overrides:item)* })?),*) => {
+        $(
+            #[async_trait]
+            impl Strategy for $name {
+                fn request_uri(&self) -> String {
+                    self.request_uri.clone()
+                }
+                fn scopes(&self) -> Vec<Scope> {
+                    self.scopes.clone()
+                }
+                fn client_id(&self) -> String {
+                    self.client_id.clone()
+                }
+
+                fn client_secret(&self) -> String {
+                    self.client_secret.clone()
+                }
+
+                fn auth_url(&self) -> String {
+                    self.auth_uri.clone()
+                }
+
+                fn redirect_url(&self) -> String {
+                    self.redirect_uri.clone()
+                }
+
+                fn failure_redirect(&self) -> Url {
+                    match self.failure_redirect.parse::<reqwest::Url>() {
+                        Ok(url) =>  url,
+                        Err(err) => panic!("{}{:?}", "Invalid Url".bold().red(), err)
+                    }
+                }
+
+                fn token_url(&self) -> Option<TokenUrl> {
+                    match TokenUrl::new(self.token_uri.clone()) {
+                        Ok(token) => Some(token),
+                        Err(err) => panic!("{}{}", "Invalid Token URL: ".bold().red(), err),
+                    }
+                }
+
+                $($($overrides)*)?
+            }
+        )*
+    };
+}
+
+strategy!(
+    GithubStrategy {
+        fn device_authorization_url(&self) -> Option<String> {
+            Some(String::from("https://github.com/login/device/code"))
+        }
+    },
+    GoogleStrategy,
+    MicrosoftStrategy {
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            vec![(String::from("prompt"), String::from("select_account"))]
+        }
+
+        // Public client flows must be allowed on the app registration.
+        fn device_authorization_url(&self) -> Option<String> {
+            Some(self.auth_uri.replace("/authorize", "/devicecode"))
+        }
+    },
+    FacebookStrategy,
+    DiscordStrategy,
+    KeycloakStrategy {
+        provider_options_overrides!("/auth/device");
+    },
+    OktaStrategy {
+        provider_options_overrides!("/device/authorize");
+    },
+    Auth0Strategy {
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            self.authorize_params.clone()
+        }
+
+        provider_options_overrides!("/device/code");
+    },
+    LinkedInStrategy {
+        // LinkedIn does not support PKCE for confidential clients and expects the credentials in the request body.
+        fn pkce(&self) -> bool {
+            false
+        }
+
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+    },
+    SlackStrategy {
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            self.authorize_params.clone()
+        }
+
+        fn map_profile(&self, profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+            self.team_profile(profile)
+        }
+    },
+    TwitchStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        // Helix rejects requests that only carry the bearer token.
+        fn profile_headers(&self) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            if let Ok(client_id) = HeaderValue::from_str(&self.client_id) {
+                headers.insert("Client-Id", client_id);
+            }
+            headers
+        }
+
+        fn map_profile(&self, profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+            match profile["data"].get(0) {
+                Some(user) => Ok(user.clone()),
+                None => anyhow::bail!("Twitch returned no user"),
+            }
+        }
+    },
+    MastodonStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        // Account ids are only unique per instance.
+        fn map_profile(&self, mut profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+            profile["instance"] = serde_json::json!(self.instance);
+            Ok(profile)
+        }
+    },
+    AtlassianStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        // The API audience and a consent prompt are required by Atlassian 3LO.
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            vec![
+                (String::from("audience"), String::from("api.atlassian.com")),
+                (String::from("prompt"), String::from("consent")),
+            ]
+        }
+
+        async fn extend_profile(
+            &self,
+            profile: serde_json::Value,
+            token: &PassportTokenResponse,
+        ) -> anyhow::Result<serde_json::Value> {
+            self.accessible_resources(profile, token).await
+        }
+    },
+    DropboxStrategy {
+        // Without it Dropbox only issues short-lived access tokens.
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            vec![(String::from("token_access_type"), String::from("offline"))]
+        }
+
+        // Dropbox RPC endpoints only accept POST, even without arguments.
+        fn profile_method(&self) -> Method {
+            Method::POST
+        }
+    },
+    SpotifyStrategy {
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            match self.show_dialog {
+                true => vec![(String::from("show_dialog"), String::from("true"))],
+                false => Vec::new(),
+            }
+        }
+    },
+    RedditStrategy {
+        // A permanent grant is what gets Reddit to issue a refresh token.
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            vec![(String::from("duration"), String::from("permanent"))]
+        }
+
+        fn token_headers(&self) -> HeaderMap {
+            self.headers()
+        }
+
+        fn profile_headers(&self) -> HeaderMap {
+            self.headers()
+        }
+    },
+    SalesforceStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        fn profile_uri(&self, token: &PassportTokenResponse) -> anyhow::Result<String> {
+            self.identity_uri(token)
+        }
+
+        // API calls go to the org's instance, not the login domain.
+        async fn extend_profile(
+            &self,
+            mut profile: serde_json::Value,
+            token: &PassportTokenResponse,
+        ) -> anyhow::Result<serde_json::Value> {
+            profile["instance_url"] = token
+                .extra_fields()
+                .get("instance_url")
+                .cloned()
+                .unwrap_or_default();
+            Ok(profile)
+        }
+    },
+    NotionStrategy {
+        // Notion only accepts the client credentials through Basic auth.
+        fn auth_type(&self) -> AuthType {
+            AuthType::BasicAuth
+        }
+
+        // Notion asks for the integration to be installed for a user rather than a workspace.
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            vec![(String::from("owner"), String::from("user"))]
+        }
+
+        fn token_profile(
+            &self,
+            token: &PassportTokenResponse,
+        ) -> Option<anyhow::Result<serde_json::Value>> {
+            Some(self.owner(token))
+        }
+    },
+    StravaStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        fn token_profile(
+            &self,
+            token: &PassportTokenResponse,
+        ) -> Option<anyhow::Result<serde_json::Value>> {
+            Some(
+                token
+                    .extra_fields()
+                    .get("athlete")
+                    .cloned()
+                    .ok_or_else(|| anyhow!("Missing athlete in Strava token response")),
+            )
+        }
+    },
+    ShopifyStrategy {
+        // Shopify supports neither PKCE nor Basic auth at the token endpoint.
+        fn pkce(&self) -> bool {
+            false
+        }
+
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            match self.access_mode {
+                ShopifyAccessMode::Online => {
+                    vec![(String::from("grant_options[]"), String::from("per-user"))]
+                }
+                ShopifyAccessMode::Offline => Vec::new(),
+            }
+        }
+
+        fn check_callback(&self, callback: &StateCode) -> anyhow::Result<()> {
+            self.verify_hmac(callback)
+        }
+
+        fn token_profile(
+            &self,
+            token: &PassportTokenResponse,
+        ) -> Option<anyhow::Result<serde_json::Value>> {
+            Some(self.shop_profile(token))
+        }
+    },
+    AmazonStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+    },
+    YahooStrategy {
+        // The Yahoo guid is the `sub` of the ID token.
+        async fn extend_profile(
+            &self,
+            mut profile: serde_json::Value,
+            token: &PassportTokenResponse,
+        ) -> anyhow::Result<serde_json::Value> {
+            profile["guid"] = id_token_claims(token, &self.client_id, Self::ISSUER)?["sub"].clone();
+            Ok(profile)
+        }
+    },
+    ZoomStrategy,
+    AzureB2cStrategy {
+        fn failure_redirect_for(&self, callback: &StateCode) -> Url {
+            if Self::password_reset_requested(callback) && !self.password_reset_redirect.is_empty() {
+                match self.password_reset_redirect.parse::<Url>() {
+                    Ok(url) => return url,
+                    Err(err) => panic!("{}{:?}", "Invalid Url".bold().red(), err),
+                }
+            }
+            self.failure_redirect()
+        }
+
+        // B2C user flows have no userinfo endpoint, the claims come with the ID token.
+        fn token_profile(
+            &self,
+            token: &PassportTokenResponse,
+        ) -> Option<anyhow::Result<serde_json::Value>> {
+            Some(id_token_claims(
+                token,
+                &self.client_id,
+                &format!("https://{}.b2clogin.com/", self.tenant),
+            ))
+        }
+    },
+    LineStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        fn authorize_params(&self) -> Vec<(String, String)> {
+            match self.bot_prompt.is_empty() {
+                true => Vec::new(),
+                false => vec![(String::from("bot_prompt"), self.bot_prompt.clone())],
+            }
+        }
+
+        async fn extend_profile(
+            &self,
+            profile: serde_json::Value,
+            token: &PassportTokenResponse,
+        ) -> anyhow::Result<serde_json::Value> {
+            self.line_profile(profile, token)
+        }
+    },
+    KakaoStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        fn map_profile(&self, profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+            self.kakao_profile(profile)
+        }
+    },
+    NaverStrategy {
+        fn auth_type(&self) -> AuthType {
+            AuthType::RequestBody
+        }
+
+        fn token_params(&self, callback: &StateCode) -> Vec<(String, String)> {
+            match callback.state.as_ref() {
+                Some(state) => vec![(String::from("state"), state.secret().clone())],
+                None => Vec::new(),
+            }
+        }
+
+        fn map_profile(&self, profile: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+            self.naver_profile(profile)
+        }
+    }
+);
+
+new_strategy!(
+    GithubStrategy,
+    GoogleStrategy,
+    MicrosoftStrategy,
+    FacebookStrategy,
+    DiscordStrategy,
+    SlackStrategy,
+    TwitchStrategy,
+    AtlassianStrategy,
+    DropboxStrategy,
+    SpotifyStrategy,
+    RedditStrategy,
+    NotionStrategy,
+    AmazonStrategy,
+    YahooStrategy,
+    ZoomStrategy,
+    LineStrategy,
+    KakaoStrategy,
+    NaverStrategy
+);
+
+// impl<C> Message for Strategies<C>
+// where
+//     C: std::marker::Unpin + ToString + Clone + Send + 'static,
+//     Self: Strategy<C> + std::marker::Unpin + Send + 'static,
+// {
+//     type Result = Option<PassPortBasicClient<Self, C>>;
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic_client::PassPortBasicClient;
+    use crate::test_server::{Response, TestServer};
+    use base64::engine::general_purpose::STANDARD;
+
+    #[test]
+    fn shopify_shop_domain() {
+        let strategy = ShopifyStrategy::new(
+            "id",
+            "hush",
+            vec!["read_products"],
+            "https://example.com/callback",
+            "https://example.com/login",
+            ShopifyAccessMode::Offline,
+        );
+        let shop = strategy.shop("My-Shop").unwrap();
+        assert_eq!(shop.shop, "my-shop.myshopify.com");
+        assert_eq!(
+            shop.token_uri,
+            "https://my-shop.myshopify.com/admin/oauth/access_token"
+        );
+        assert!(strategy.shop("a.myshopify.com.myshopify.com").is_err());
+        assert!(strategy.shop("evil.com/x.myshopify.com").is_err());
+        assert!(strategy.shop("-shop").is_err());
+        assert!(strategy.shop(".myshopify.com").is_err());
+    }
+
+    #[test]
+    fn provider_options() {
+        let keycloak = KeycloakStrategy::new(
+            "https://sso.example.com",
+            "demo",
+            "id",
+            "secret",
+            vec!["openid"],
+            "https://example.com/callback",
+            "https://example.com/login",
+        )
+        .pushed_authorization_requests()
+        .dpop()
+        .client_auth_method(ClientAuthMethod::ClientSecretPost);
+        assert_eq!(
+            keycloak.pushed_authorization_url().as_deref(),
+            Some("https://sso.example.com/realms/demo/protocol/openid-connect/ext/par/request")
+        );
+        assert!(Strategy::dpop(&keycloak));
+        assert_eq!(
+            Strategy::client_auth_method(&keycloak).name(),
+            "client_secret_post"
+        );
+        assert_eq!(
+            keycloak.revocation_url().as_deref(),
+            Some("https://sso.example.com/realms/demo/protocol/openid-connect/revoke")
+        );
+        assert_eq!(
+            keycloak.device_authorization_url().as_deref(),
+            Some("https://sso.example.com/realms/demo/protocol/openid-connect/auth/device")
+        );
+
+        let okta = OktaStrategy::new(
+            "dev-123456.okta.com",
+            Some("default"),
+            "id",
+            "secret",
+            vec!["openid"],
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        assert_eq!(okta.pushed_authorization_url(), None);
+        assert!(!Strategy::dpop(&okta));
+        assert_eq!(
+            Strategy::client_auth_method(&okta).name(),
+            "client_secret_basic"
+        );
+        assert_eq!(
+            okta.revocation_url().as_deref(),
+            Some("https://dev-123456.okta.com/oauth2/default/v1/revoke")
+        );
+        assert_eq!(
+            okta.device_authorization_url().as_deref(),
+            Some("https://dev-123456.okta.com/oauth2/default/v1/device/authorize")
+        );
+        assert_eq!(
+            okta.clone()
+                .pushed_authorization_requests()
+                .pushed_authorization_url()
+                .as_deref(),
+            Some("https://dev-123456.okta.com/oauth2/default/v1/par")
+        );
+        // Only a trailing `/token` is swapped, and without it there is no telling where the endpoint is.
+        let keycloak = KeycloakStrategy::new(
+            "https://example.com/token",
+            "demo",
+            "id",
+            "secret",
+            vec!["openid"],
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        assert_eq!(
+            keycloak.revocation_url().as_deref(),
+            Some("https://example.com/token/realms/demo/protocol/openid-connect/revoke")
+        );
+        let okta = OktaStrategy {
+            token_uri: String::from("https://dev-123456.okta.com/oauth2/v1/access"),
+            ..okta
+        };
+        assert_eq!(okta.revocation_url(), None);
+    }
+
+    #[test]
+    fn amazon_regions() {
+        let endpoints = |region| {
+            let strategy = AmazonStrategy::default().region(region);
+            (strategy.auth_uri, strategy.token_uri, strategy.request_uri)
+        };
+        assert_eq!(
+            endpoints(AmazonRegion::NorthAmerica),
+            (
+                String::from("https://www.amazon.com/ap/oa"),
+                String::from("https://api.amazon.com/auth/o2/token"),
+                String::from("https://api.amazon.com/user/profile"),
+            )
+        );
+        assert_eq!(
+            endpoints(AmazonRegion::Europe),
+            (
+                String::from("https://eu.account.amazon.com/ap/oa"),
+                String::from("https://api.amazon.co.uk/auth/o2/token"),
+                String::from("https://api.amazon.co.uk/user/profile"),
+            )
+        );
+        assert_eq!(
+            endpoints(AmazonRegion::FarEast),
+            (
+                String::from("https://apac.account.amazon.com/ap/oa"),
+                String::from("https://api.amazon.co.jp/auth/o2/token"),
+                String::from("https://api.amazon.co.jp/user/profile"),
+            )
+        );
+    }
+
+    // Token response carrying an unsigned ID token with `claims`.
+    fn id_token(claims: serde_json::Value) -> PassportTokenResponse {
+        serde_json::from_value(serde_json::json!({
+            "access_token": "access",
+            "token_type": "bearer",
+            "id_token": format!(
+                "eyJhbGciOiJFUzI1NiJ9.{}.c2lnbmF0dXJl",
+                URL_SAFE_NO_PAD.encode(claims.to_string())
+            ),
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn yahoo_guid_from_id_token() {
+        let strategy = YahooStrategy {
+            client_id: String::from("id"),
+            ..Default::default()
+        };
+        let token = id_token(serde_json::json!({
+            "sub": "ABCDEFGHIJKLMNOP",
+            "aud": "id",
+            "iss": "https://api.login.yahoo.com",
+        }));
+        let profile = strategy
+            .extend_profile(serde_json::json!({ "name": "Jane" }), &token)
+            .await
+            .unwrap();
+        assert_eq!(profile["guid"], "ABCDEFGHIJKLMNOP");
+        assert_eq!(profile["name"], "Jane");
+    }
+
+    #[test]
+    fn id_token_audience_and_issuer() {
+        let yahoo = "https://api.login.yahoo.com";
+        let claims = |aud: serde_json::Value, iss: &str| {
+            id_token_claims(
+                &id_token(serde_json::json!({ "sub": "user", "aud": aud, "iss": iss })),
+                "id",
+                yahoo,
+            )
+        };
+        assert_eq!(
+            claims(serde_json::json!("id"), yahoo).unwrap()["sub"],
+            "user"
+        );
+        assert!(claims(serde_json::json!(["other", "id"]), yahoo).is_ok());
+        assert_eq!(
+            claims(serde_json::json!("other"), yahoo)
+                .err()
+                .unwrap()
+                .to_string(),
+            "id_token was not issued to this client"
+        );
+        assert!(claims(serde_json::json!(["other"]), yahoo).is_err());
+        assert_eq!(
+            claims(serde_json::json!("id"), "https://access.line.me")
+                .err()
+                .unwrap()
+                .to_string(),
+            "id_token from unexpected issuer https://access.line.me"
+        );
+        assert!(claims(
+            serde_json::json!("id"),
+            "https://api.login.yahoo.com.evil.com"
+        )
+        .is_err());
+
+        // B2C issuers end with the tenant id, only the tenant's host is known up front.
+        let b2c = |iss: &str| {
+            id_token_claims(
+                &id_token(serde_json::json!({ "aud": "id", "iss": iss })),
+                "id",
+                "https://contoso.b2clogin.com/",
+            )
+        };
+        assert!(
+            b2c("https://contoso.b2clogin.com/775527ff-9a37-4307-8b3d-cc311f58d925/v2.0/").is_ok()
+        );
+        assert!(
+            b2c("https://fabrikam.b2clogin.com/775527ff-9a37-4307-8b3d-cc311f58d925/v2.0/")
+                .is_err()
+        );
+
+        let token = serde_json::from_value::<PassportTokenResponse>(serde_json::json!({
+            "access_token": "access",
+            "token_type": "bearer",
+        }))
+        .unwrap();
+        assert!(id_token_claims(&token, "id", yahoo).is_err());
+    }
+
+    #[tokio::test]
+    async fn azure_b2c_password_reset() {
+        let mut passport = PassPortBasicClient::default();
+        passport.using(
+            "b2c",
+            AzureB2cStrategy::new(
+                "contoso.onmicrosoft.com",
+                "B2C_1_signupsignin",
+                "id",
+                "secret",
+                vec!["openid"],
+                "https://example.com/callback",
+                "https://example.com/login",
+            )
+            .password_reset_redirect("https://example.com/auth/reset"),
+        );
+        passport.authenticate("b2c");
+        let url = passport.generate_redirect_url().parse::<Url>().unwrap();
+        assert_eq!(
+            url.path(),
+            "/contoso.onmicrosoft.com/b2c_1_signupsignin/oauth2/v2.0/authorize"
+        );
+        let state = url
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, state)| state.into_owned())
+            .unwrap();
+        let callback = |error: &str, description: &str| StateCode {
+            state: Some(CsrfToken::new(state.clone())),
+            code: None,
+            params: HashMap::from([
+                (String::from("error"), error.to_string()),
+                (String::from("error_description"), description.to_string()),
+            ]),
+        };
+        let redirect = |response: anyhow::Result<PassportResponse>| match response.unwrap() {
+            PassportResponse::FailureRedirect(url) => url.to_string(),
+            PassportResponse::Profile(_) => panic!("Unexpected profile"),
+        };
+
+        let reset = callback(
+            "access_denied",
+            "AADB2C90118: The user has forgotten their password.\r\nCorrelation ID: 8b8f4b4e",
+        );
+        assert!(AzureB2cStrategy::password_reset_requested(&reset));
+        assert_eq!(
+            redirect(passport.get_profile(reset).await),
+            "https://example.com/auth/reset"
+        );
+        // Cancelling the sign up is an `access_denied` too, it goes to the regular failure redirect.
+        let cancel = callback(
+            "access_denied",
+            "AADB2C90091: The user has cancelled entering self-asserted information.",
+        );
+        assert!(!AzureB2cStrategy::password_reset_requested(&cancel));
+        assert_eq!(
+            redirect(passport.get_profile(cancel).await),
+            "https://example.com/login"
+        );
+        let error = callback(
+            "server_error",
+            "AADB2C90037: An error occurred while processing the request.",
+        );
+        assert_eq!(
+            redirect(passport.get_profile(error).await),
+            "https://example.com/login"
+        );
+        // Without a reset redirect the user lands on the failure redirect.
+        let strategy = AzureB2cStrategy::new(
+            "contoso",
+            "B2C_1_signupsignin",
+            "id",
+            "secret",
+            vec!["openid"],
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        assert_eq!(
+            strategy
+                .failure_redirect_for(&callback("access_denied", "AADB2C90118: Forgot password"))
+                .as_str(),
+            "https://example.com/login"
+        );
+    }
+
+    #[test]
+    fn line_profile() {
+        let strategy = LineStrategy {
+            client_id: String::from("1234567890"),
+            ..Default::default()
+        };
+        let profile = serde_json::json!({
+            "userId": "U4af4980629",
+            "displayName": "Brown",
+            "pictureUrl": "https://profile.line-scdn.net/abcdefghijklmn",
+        });
+        let token = id_token(serde_json::json!({
+            "sub": "U4af4980629",
+            "aud": "1234567890",
+            "iss": "https://access.line.me",
+            "email": "brown@example.com",
+        }));
+        let user = strategy.line_profile(profile.clone(), &token).unwrap();
+        assert_eq!(user["id"], "U4af4980629");
+        assert_eq!(user["name"], "Brown");
+        assert_eq!(user["email"], "brown@example.com");
+        assert_eq!(
+            user["picture"],
+            "https://profile.line-scdn.net/abcdefghijklmn"
+        );
+
+        // Without the `openid` scope there is no ID token, and no email.
+        let token = serde_json::from_value::<PassportTokenResponse>(serde_json::json!({
+            "access_token": "access",
+            "token_type": "Bearer",
+        }))
+        .unwrap();
+        let user = strategy.line_profile(profile.clone(), &token).unwrap();
+        assert_eq!(user["id"], "U4af4980629");
+        assert!(user["email"].is_null());
+
+        let token = id_token(serde_json::json!({
+            "aud": "another channel",
+            "iss": "https://access.line.me",
+            "email": "brown@example.com",
+        }));
+        assert!(strategy.line_profile(profile, &token).is_err());
+    }
+
+    #[test]
+    fn kakao_profile() {
+        let strategy = KakaoStrategy::default();
+        let user = strategy
+            .kakao_profile(serde_json::json!({
+                "id": 123456789,
+                "kakao_account": {
+                    "profile": {
+                        "nickname": "Ryan",
+                        "profile_image_url": "http://k.kakaocdn.net/img_640x640.jpg",
+                    },
+                    "email": "ryan@example.com",
+                },
+            }))
+            .unwrap();
+        assert_eq!(user["id"], "123456789");
+        assert_eq!(user["name"], "Ryan");
+        assert_eq!(user["email"], "ryan@example.com");
+        assert_eq!(user["picture"], "http://k.kakaocdn.net/img_640x640.jpg");
+
+        // The email is only there when the user agreed to share it.
+        let user = strategy
+            .kakao_profile(serde_json::json!({
+                "id": 123456789,
+                "kakao_account": { "profile": { "nickname": "Ryan" } },
+            }))
+            .unwrap();
+        assert_eq!(user["name"], "Ryan");
+        assert!(user["email"].is_null());
+        let user = strategy
+            .kakao_profile(serde_json::json!({ "id": 123456789 }))
+            .unwrap();
+        assert_eq!(user["id"], "123456789");
+        assert!(user["email"].is_null());
+        assert!(user["name"].is_null());
+
+        assert!(strategy
+            .kakao_profile(
+                serde_json::json!({ "msg": "this access token does not exist", "code": -401 })
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn naver_profile() {
+        let strategy = NaverStrategy::default();
+        let user = strategy
+            .naver_profile(serde_json::json!({
+                "resultcode": "00",
+                "message": "success",
+                "response": {
+                    "id": "32742776",
+                    "nickname": "OpenAPI",
+                    "email": "openapi@naver.com",
+                    "profile_image": "https://ssl.pstatic.net/static/pwe/address/nodata_33x33.gif",
+                },
+            }))
+            .unwrap();
+        assert_eq!(user["id"], "32742776");
+        // Without a `name` the nickname stands in.
+        assert_eq!(user["name"], "OpenAPI");
+        assert_eq!(user["email"], "openapi@naver.com");
+        assert_eq!(
+            user["picture"],
+            "https://ssl.pstatic.net/static/pwe/address/nodata_33x33.gif"
+        );
+
+        let error = strategy
+            .naver_profile(serde_json::json!({
+                "resultcode": "024",
+                "message": "Authentication failed",
+            }))
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Naver profile request failed: Authentication failed"
+        );
+    }
+
+    #[test]
+    fn linkedin_scopes() {
+        let scopes = |requested: Vec<&str>| {
+            LinkedInStrategy::new(
+                "id",
+                "secret",
+                requested,
+                "https://example.com/callback",
+                "https://example.com/login",
+            )
+            .scopes
+            .iter()
+            .map(|scope| scope.to_string())
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(scopes(vec![]), ["openid", "profile", "email"]);
+        // Requested OpenID scopes are not sent twice, others follow the forced ones.
+        assert_eq!(
+            scopes(vec!["email", "w_member_social", "openid"]),
+            ["openid", "profile", "email", "w_member_social"]
+        );
+    }
+
+    #[test]
+    fn slack_team_profile() {
+        let profile = serde_json::json!({
+            "ok": true,
+            "sub": "U0R7JM",
+            "email": "krane@example.com",
+            "https://slack.com/team_id": "T0R7GR",
+            "https://slack.com/team_name": "Umbrella Corporation",
+            "https://slack.com/team_domain": "umbrellacorp",
+            "https://slack.com/team_image_88": "https://secure.gravatar.com/avatar/88.png",
+            "https://slack.com/team_image_44": "https://secure.gravatar.com/avatar/44.png",
+        });
+        let strategy = SlackStrategy::default().allowed_teams(vec!["T0R7GR", "T0AAAA"]);
+        let user = strategy.team_profile(profile.clone()).unwrap();
+        assert_eq!(user["sub"], "U0R7JM");
+        assert_eq!(user["team_id"], "T0R7GR");
+        assert_eq!(user["team_name"], "Umbrella Corporation");
+        assert_eq!(user["team_domain"], "umbrellacorp");
+        assert_eq!(
+            user["team_avatar"],
+            "https://secure.gravatar.com/avatar/88.png"
+        );
+        // Without allowed teams every workspace signs in.
+        assert!(SlackStrategy::default()
+            .team_profile(profile.clone())
+            .is_ok());
+
+        let error = SlackStrategy::default()
+            .allowed_teams(vec!["T0AAAA"])
+            .team_profile(profile)
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Slack workspace T0R7GR is not allowed");
+        let error = strategy
+            .team_profile(serde_json::json!({ "ok": false, "error": "invalid_auth" }))
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Slack userInfo failed: invalid_auth");
+        assert!(strategy
+            .team_profile(serde_json::json!({ "ok": true, "sub": "U0R7JM" }))
+            .is_err());
+    }
+
+    #[test]
+    fn mastodon_domain() {
+        for (instance, domain) in [
+            ("mastodon.social", "mastodon.social"),
+            (" https://Mastodon.Social/ ", "mastodon.social"),
+            ("fosstodon.org:8443", "fosstodon.org:8443"),
+            ("https://mstdn.jp:443", "mstdn.jp"),
+        ] {
+            assert_eq!(MastodonStrategy::domain(instance).unwrap(), domain);
+        }
+        for instance in [
+            "",
+            "localhost",
+            "https://intranet/",
+            "127.0.0.1",
+            "10.0.0.1:3000",
+            "0x7f.1",
+            "[::1]",
+            "mastodon..social",
+            ".mastodon.social",
+            "mastodon.social.",
+            "http://mastodon.social",
+            "mastodon.social/@user",
+            "user@mastodon.social",
+            "mastodon.social?next=/",
+        ] {
+            assert!(MastodonStrategy::domain(instance).is_err(), "{}", instance);
+        }
+    }
+
+    #[tokio::test]
+    async fn zoom_profile() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/oauth/token" => Response::json(
+                200,
+                serde_json::json!({
+                    "access_token": "access",
+                    "token_type": "bearer",
+                    "refresh_token": "refresh",
+                    "expires_in": 3599,
+                    "scope": "user:read",
+                }),
+            ),
+            "/v2/users/me" if request.header("authorization") == Some("Bearer access") => {
+                Response::json(
+                    200,
+                    serde_json::json!({
+                        "id": "KDcuGIm1QgePTO8WbOqwIQ",
+                        "first_name": "Jane",
+                        "last_name": "Doe",
+                        "email": "jane@example.com",
+                        "account_id": "q6gBJVO5TzexKYTb_I2rpg",
+                    }),
+                )
+            }
+            _ => Response::json(
+                401,
+                serde_json::json!({ "code": 124, "message": "Invalid access token." }),
+            ),
+        })
+        .await;
+        let strategy = ZoomStrategy {
+            client_id: String::from("id"),
+            client_secret: String::from("secret"),
+            token_uri: server.url("/oauth/token"),
+            request_uri: server.url("/v2/users/me"),
+            redirect_uri: String::from("https://example.com/callback"),
+            failure_redirect: String::from("https://example.com/login"),
+            ..Default::default()
+        };
+        let mut passport = PassPortBasicClient::default();
+        passport.using("zoom", strategy);
+        passport.authenticate("zoom");
+        let url = passport.generate_redirect_url().parse::<Url>().unwrap();
+        assert!(url.as_str().starts_with("https://zoom.us/oauth/authorize?"));
+        let state = url
+            .query_pairs()
+            .find(|(key, _)| key == "state")
+            .map(|(_, state)| state.into_owned())
+            .unwrap();
+        let response = passport
+            .get_profile(StateCode {
+                state: Some(CsrfToken::new(state)),
+                code: Some(String::from("code")),
+                params: HashMap::new(),
+            })
+            .await
+            .unwrap();
+        let profile = match response {
+            PassportResponse::Profile(profile) => profile,
+            PassportResponse::FailureRedirect(url) => panic!("Unexpected redirect to {}", url),
+        };
+        assert_eq!(profile["id"], "KDcuGIm1QgePTO8WbOqwIQ");
+        assert_eq!(profile["email"], "jane@example.com");
+        assert_eq!(profile["access_token"], "access");
+        assert_eq!(profile["refresh_token"], "refresh");
+        // Zoom takes the client credentials in the Authorization header.
+        let token_request = &server.requests()[0];
+        assert_eq!(
+            token_request.header("authorization"),
+            Some(format!("Basic {}", STANDARD.encode("id:secret")).as_str())
+        );
+        assert!(!token_request.form().contains_key("client_secret"));
+        assert_eq!(token_request.form()["code"], "code");
+    }
+
+    // Example from Shopify's OAuth documentation.
+    #[test]
+    fn shopify_hmac() {
+        let strategy = ShopifyStrategy::new(
+            "id",
+            "hush",
+            vec![],
+            "https://example.com/callback",
+            "https://example.com/login",
+            ShopifyAccessMode::Offline,
+        )
+        .shop("some-shop")
+        .unwrap();
+        let query = |hmac: &str| StateCode {
+            state: Some(CsrfToken::new(String::from("0.6784241404160823"))),
+            code: Some(String::from("0907a61c0c8d55e99db179b68161bc00")),
+            params: HashMap::from([
+                (
+                    String::from("shop"),
+                    String::from("some-shop.myshopify.com"),
+                ),
+                (String::from("timestamp"), String::from("1337178173")),
+                (String::from("hmac"), hmac.to_string()),
+            ]),
+        };
+        strategy
+            .verify_signature(&query(
+                "700e2dadb827fcc8609e9d5ce208b2e9cdaab9df07390d2cbca10d7c328fc4bf",
+            ))
+            .unwrap();
+        assert!(strategy
+            .verify_signature(&query(
+                "700e2dadb827fcc8609e9d5ce208b2e9cdaab9df07390d2cbca10d7c328fc4be"
+            ))
+            .is_err());
+        // Signed years ago, the query is refused as a whole.
+        assert_eq!(
+            strategy
+                .verify_hmac(&query(
+                    "700e2dadb827fcc8609e9d5ce208b2e9cdaab9df07390d2cbca10d7c328fc4bf"
+                ))
+                .err()
+                .unwrap()
+                .to_string(),
+            "Outdated Shopify query"
+        );
+    }
+
+    // Callback query for `shop` signed with the app secret at `timestamp`.
+    fn shopify_callback(shop: &str, timestamp: i64) -> StateCode {
+        let mut params = [
+            (
+                String::from("code"),
+                String::from("0907a61c0c8d55e99db179b68161bc00"),
+            ),
+            (String::from("shop"), shop.to_string()),
+            (String::from("state"), String::from("state")),
+            (String::from("timestamp"), timestamp.to_string()),
+        ];
+        params.sort();
+        let message = params
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"hush").unwrap();
+        mac.update(message.as_bytes());
+        StateCode {
+            state: Some(CsrfToken::new(String::from("state"))),
+            code: Some(String::from("0907a61c0c8d55e99db179b68161bc00")),
+            params: HashMap::from([
+                (String::from("shop"), shop.to_string()),
+                (String::from("timestamp"), timestamp.to_string()),
+                (
+                    String::from("hmac"),
+                    hex::encode(mac.finalize().into_bytes()),
+                ),
+            ]),
+        }
+    }
+
+    #[test]
+    fn shopify_callback_checks() {
+        let strategy = ShopifyStrategy::new(
+            "id",
+            "hush",
+            vec![],
+            "https://example.com/callback",
+            "https://example.com/login",
+            ShopifyAccessMode::Offline,
+        )
+        .shop("some-shop")
+        .unwrap();
+        let now = chrono::Utc::now().timestamp();
+        let error = |query: StateCode| strategy.check_callback(&query).err().unwrap().to_string();
+        assert!(strategy
+            .check_callback(&shopify_callback("some-shop.myshopify.com", now))
+            .is_ok());
+
+        // Validly signed, but for a shop that is not a `*.myshopify.com` one or not this one.
+        assert_eq!(
+            error(shopify_callback("evil.com", now)),
+            "Invalid Shopify shop: evil.com.myshopify.com"
+        );
+        assert_eq!(
+            error(shopify_callback("some-shop.myshopify.com.evil.com", now)),
+            "Invalid Shopify shop: some-shop.myshopify.com.evil.com.myshopify.com"
+        );
+        assert_eq!(
+            error(shopify_callback("other-shop.myshopify.com", now)),
+            "Unexpected Shopify shop: other-shop.myshopify.com"
+        );
+
+        assert_eq!(
+            error(shopify_callback("some-shop.myshopify.com", now - 600)),
+            "Outdated Shopify query"
+        );
+        assert_eq!(
+            error(shopify_callback("some-shop.myshopify.com", now + 600)),
+            "Outdated Shopify query"
+        );
+        let mut tampered = shopify_callback("some-shop.myshopify.com", now);
+        tampered.code = Some(String::from("another code"));
+        assert_eq!(error(tampered), "Shopify hmac verification failed");
+    }
+
+    // Login data as the widget sends it, signed with the bot token.
+    fn telegram_callback(auth_date: i64) -> StateCode {
+        let mut params = HashMap::from([
+            (String::from("id"), String::from("123456789")),
+            (String::from("first_name"), String::from("Jane")),
+            (String::from("username"), String::from("jane")),
+            (String::from("auth_date"), auth_date.to_string()),
+        ]);
+        let mut fields = params.iter().collect::<Vec<_>>();
+        fields.sort();
+        let check = fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(b"123456789:token")).unwrap();
+        mac.update(check.as_bytes());
+        params.insert(
+            String::from("hash"),
+            hex::encode(mac.finalize().into_bytes()),
+        );
+        StateCode {
+            state: None,
+            code: None,
+            params,
+        }
+    }
+
+    #[test]
+    fn wechat_authorize_url() {
+        let strategy = WechatStrategy::new(
+            "wx520c15f417810387",
+            "secret",
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        let url = strategy
+            .authorize_redirect(&CsrfToken::new(String::from(
+                "3d6be0a4035d839573b04816624a415e",
+            )))
+            .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://open.weixin.qq.com/connect/qrconnect?appid=wx520c15f417810387\
+             &redirect_uri=https%3A%2F%2Fexample.com%2Fcallback&response_type=code&scope=snsapi_login\
+             &state=3d6be0a4035d839573b04816624a415e#wechat_redirect"
+        );
+    }
+
+    #[tokio::test]
+    async fn wechat_sign_in() {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/sns/oauth2/access_token" if request.query("code") == Some("expired") => {
+                Response::json(
+                    200,
+                    serde_json::json!({ "errcode": 40029, "errmsg": "invalid code" }),
+                )
+            }
+            "/sns/oauth2/access_token" => Response::json(
+                200,
+                serde_json::json!({
+                    "access_token": "ACCESS_TOKEN",
+                    "expires_in": 7200,
+                    "refresh_token": "REFRESH_TOKEN",
+                    "openid": "OPENID",
+                    "scope": "snsapi_login",
+                    "unionid": "o6_bmasdasdsad6_2sgVt7hMZOPfL",
+                }),
+            ),
+            _ => Response::json(
+                200,
+                serde_json::json!({
+                    "openid": "OPENID",
+                    "nickname": "NICKNAME",
+                    "headimgurl": "https://thirdwx.qlogo.cn/mmopen/0",
+                }),
+            ),
+        })
+        .await;
+        let mut strategy = WechatStrategy::new(
+            "wx520c15f417810387",
+            "secret",
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        strategy.token_uri = server.url("/sns/oauth2/access_token");
+        strategy.request_uri = server.url("/sns/userinfo");
+        let callback = |code: &str| StateCode {
+            state: Some(CsrfToken::new(String::from("state"))),
+            code: Some(code.to_string()),
+            params: HashMap::new(),
+        };
+
+        let profile = match strategy.exchange(&callback("code")).await.unwrap() {
+            PassportResponse::Profile(profile) => profile,
+            _ => panic!("expected a profile"),
+        };
+        assert_eq!(profile["nickname"], "NICKNAME");
+        assert_eq!(profile["unionid"], "o6_bmasdasdsad6_2sgVt7hMZOPfL");
+        assert_eq!(profile["access_token"], "ACCESS_TOKEN");
+        assert_eq!(profile["refresh_token"], "REFRESH_TOKEN");
+        let token = &server.requests()[0];
+        assert_eq!(token.query("appid"), Some("wx520c15f417810387"));
+        assert_eq!(token.query("secret"), Some("secret"));
+        assert_eq!(token.query("grant_type"), Some("authorization_code"));
+        let userinfo = &server.requests()[1];
+        assert_eq!(userinfo.query("access_token"), Some("ACCESS_TOKEN"));
+        assert_eq!(userinfo.query("openid"), Some("OPENID"));
+
+        // Errors come back as `200 OK` with an errcode.
+        let error = strategy.exchange(&callback("expired")).await.err().unwrap();
+        assert_eq!(error.to_string(), "WeChat error 40029: invalid code");
+        assert!(matches!(
+            strategy
+                .exchange(&StateCode {
+                    code: None,
+                    ..callback("code")
+                })
+                .await
+                .unwrap(),
+            PassportResponse::FailureRedirect(_)
+        ));
+    }
+
+    #[test]
+    fn telegram_login_data() {
+        let strategy = TelegramStrategy::new(
+            "123456789:token",
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        let now = chrono::Utc::now().timestamp();
+        let profile = match strategy.verify(&telegram_callback(now)).unwrap() {
+            PassportResponse::Profile(profile) => profile,
+            PassportResponse::FailureRedirect(url) => panic!("Unexpected redirect to {}", url),
+        };
+        assert_eq!(profile["id"], 123456789);
+        assert_eq!(profile["first_name"], "Jane");
+        assert_eq!(profile["auth_date"], now);
+        assert!(profile.get("hash").is_none());
+
+        let mut tampered = telegram_callback(now);
+        tampered
+            .params
+            .insert(String::from("username"), String::from("admin"));
+        assert_eq!(
+            strategy.verify(&tampered).err().unwrap().to_string(),
+            "Telegram login data failed hash verification"
+        );
+
+        // Validly signed, but older than the five minutes accepted by default.
+        let stale = telegram_callback(now - 600);
+        assert_eq!(
+            strategy.verify(&stale).err().unwrap().to_string(),
+            "Telegram login data is outdated"
+        );
+        assert!(strategy.clone().max_age(3600).verify(&stale).is_ok());
+
+        let mut unsigned = telegram_callback(now);
+        unsigned.params.remove("hash");
+        assert!(matches!(
+            strategy.verify(&unsigned).unwrap(),
+            PassportResponse::FailureRedirect(_)
+        ));
+    }
+
+    // Steam's OpenID endpoint confirming every assertion, and the player summaries.
+    async fn steam() -> (TestServer, SteamStrategy) {
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/openid/login" => {
+                Response::text(200, "ns:http://specs.openid.net/auth/2.0\nis_valid:true\n")
+            }
+            _ => Response::json(
+                200,
+                serde_json::json!({
+                    "response": { "players": [{ "personaname": "Jane" }] }
+                }),
+            ),
+        })
+        .await;
+        let mut strategy = SteamStrategy::new(
+            "key",
+            "https://example.com",
+            "https://example.com/callback",
+            "https://example.com/login",
+        );
+        strategy.auth_uri = server.url("/openid/login");
+        strategy.request_uri = server.url("/summaries");
+        (server, strategy)
+    }
+
+    fn steam_callback(strategy: &SteamStrategy, issued: i64) -> StateCode {
+        let state = CsrfToken::new(String::from("state"));
+        let nonce = format!(
+            "{}{}",
+            chrono::DateTime::from_timestamp(chrono::Utc::now().timestamp() + issued, 0)
+                .unwrap()
+                .format("%Y-%m-%dT%H:%M:%SZ"),
+            rand::random::<u32>()
+        );
+        let params = [
+            ("openid.ns", SteamStrategy::OPENID_NS),
+            ("openid.mode", "id_res"),
+            ("openid.op_endpoint", &strategy.auth_uri),
+            (
+                "openid.claimed_id",
+                "https://steamcommunity.com/openid/id/76561197960435530",
+            ),
+            (
+                "openid.identity",
+                "https://steamcommunity.com/openid/id/76561197960435530",
+            ),
+            (
+                "openid.return_to",
+                "https://example.com/callback?state=state",
+            ),
+            ("openid.response_nonce", &nonce),
+            ("openid.assoc_handle", "1234567890"),
+            (
+                "openid.signed",
+                "signed,op_endpoint,claimed_id,identity,return_to,response_nonce,assoc_handle",
+            ),
+            ("openid.sig", "c2lnbmF0dXJl"),
+        ];
+        StateCode {
+            state: Some(state),
+            code: None,
+            params: params
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn steam_sign_in() {
+        let (server, strategy) = steam().await;
+        let callback = steam_callback(&strategy, 0);
+        let profile = match strategy.verify(&callback).await.unwrap() {
+            PassportResponse::Profile(profile) => profile,
+            PassportResponse::FailureRedirect(url) => panic!("Unexpected redirect to {}", url),
+        };
+        assert_eq!(profile["steamid"], "76561197960435530");
+        assert_eq!(profile["personaname"], "Jane");
+        let verification = &server.requests()[0];
+        assert_eq!(verification.form()["openid.mode"], "check_authentication");
+        assert_eq!(verification.form()["openid.sig"], "c2lnbmF0dXJl");
+
+        let error = strategy.verify(&callback).await.err().unwrap();
+        assert_eq!(error.to_string(), "Replayed OpenID response nonce");
+        // A replay is refused before Steam is asked again.
+        assert_eq!(server.count("/openid/login"), 1);
+    }
+
+    #[tokio::test]
+    async fn steam_rejects_stale_nonces() {
+        let (server, strategy) = steam().await;
+        let error = strategy
+            .verify(&steam_callback(
+                &strategy,
+                -SteamStrategy::NONCE_MAX_AGE - 60,
+            ))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.to_string(), "Expired OpenID response nonce");
+        let error = strategy
+            .verify(&steam_callback(
+                &strategy,
+                SteamStrategy::NONCE_MAX_SKEW + 30,
+            ))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "OpenID response nonce dated in the future"
+        );
+        let mut callback = steam_callback(&strategy, 0);
+        callback.params.insert(
+            String::from("openid.response_nonce"),
+            String::from("yesterday"),
+        );
+        let error = strategy.verify(&callback).await.err().unwrap();
+        assert_eq!(error.to_string(), "Invalid OpenID response nonce");
+        // Within the skew the nonce is fine.
+        assert!(strategy
+            .verify(&steam_callback(
+                &strategy,
+                SteamStrategy::NONCE_MAX_SKEW - 10
+            ))
+            .await
+            .is_ok());
+        assert_eq!(server.count("/openid/login"), 1);
+    }
+
+    #[tokio::test]
+    async fn steam_rejects_forged_callbacks() {
+        let (server, strategy) = steam().await;
+        let forged = |key: &str, value: &str| {
+            let mut callback = steam_callback(&strategy, 0);
+            callback.params.insert(key.to_string(), value.to_string());
+            callback
+        };
+        let error = |callback: StateCode| {
+            let strategy = strategy.clone();
+            async move { strategy.verify(&callback).await.err().unwrap().to_string() }
+        };
+        assert_eq!(
+            error(forged(
+                "openid.op_endpoint",
+                "https://evil.example.com/openid/login"
+            ))
+            .await,
+            "Unexpected OpenID provider endpoint"
+        );
+        assert_eq!(
+            error(forged(
+                "openid.return_to",
+                "https://example.com/callback?state=other"
+            ))
+            .await,
+            "OpenID return_to does not match the redirect url"
+        );
+        assert_eq!(
+            error(forged(
+                "openid.return_to",
+                "https://evil.example.com/callback?state=state"
+            ))
+            .await,
+            "OpenID return_to does not match the redirect url"
+        );
+        let mut callback = steam_callback(&strategy, 0);
+        callback.state = Some(CsrfToken::new(String::from("other")));
+        assert_eq!(
+            error(callback).await,
+            "OpenID return_to does not match the redirect url"
+        );
+        for claimed_id in [
+            "https://steamcommunity.com/openid/id/",
+            "https://steamcommunity.com/openid/id/7656119796043553a",
+            "https://evil.example.com/openid/id/76561197960435530",
+        ] {
+            assert_eq!(
+                error(forged("openid.claimed_id", claimed_id)).await,
+                "Invalid SteamID in OpenID claimed_id"
+            );
+        }
+        // Cancelled sign ins go back to the failure redirect.
+        let response = strategy
+            .verify(&forged("openid.mode", "cancel"))
+            .await
+            .unwrap();
+        assert!(
+            matches!(response, PassportResponse::FailureRedirect(url) if url.as_str() == "https://example.com/login")
+        );
+        assert!(server.requests().is_empty());
+    }
+
+    // Local stand-in for a PDS that is its own authorization server, served over http on the loopback host.
+    struct FakePds {
+        origin: String,
+        did: String,
+        // DID the token endpoint signs in, the PDS's own by default.
+        sub: Mutex<Option<String>>,
+        par_requests: std::sync::atomic::AtomicUsize,
+    }
+
+    impl FakePds {
+        const NONCE: &'static str = "server-nonce";
+
+        async fn start() -> Arc<Self> {
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", 0))
+                .await
+                .unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let pds = Arc::new(Self {
+                origin: format!("http://127.0.0.1:{}", port),
+                did: format!("did:web:127.0.0.1%3A{}", port),
+                sub: Mutex::new(None),
+                par_requests: std::sync::atomic::AtomicUsize::new(0),
+            });
+            let server = Arc::clone(&pds);
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    tokio::spawn(Arc::clone(&server).serve(stream));
+                }
+            });
+            pds
+        }
+
+        async fn serve(self: Arc<Self>, mut stream: tokio::net::TcpStream) {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            let head_end = loop {
+                if let Some(end) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break end + 4;
+                }
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..head_end]).to_string();
+            let headers = head
+                .lines()
+                .skip(1)
+                .filter_map(|line| line.split_once(": "))
+                .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+                .collect::<HashMap<_, _>>();
+            let length = headers
+                .get("content-length")
+                .and_then(|length| length.parse::<usize>().ok())
+                .unwrap_or(0);
+            while request.len() < head_end + length {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let mut request_line = head.split(' ');
+            let method = request_line.next().unwrap_or_default();
+            let path = request_line
+                .next()
+                .unwrap_or_default()
+                .split('?')
+                .next()
+                .unwrap_or_default();
+            let (status, body) = self.route(method, path, &headers);
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nDPoP-Nonce: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                Self::NONCE,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.shutdown().await;
+        }
+
+        fn route(
+            &self,
+            method: &str,
+            path: &str,
+            headers: &HashMap<String, String>,
+        ) -> (&'static str, String) {
+            // Every DPoP request must carry the nonce this server hands out.
+            let nonce = headers
+                .get("dpop")
+                .and_then(|proof| proof.split('.').nth(1))
+                .and_then(|claims| URL_SAFE_NO_PAD.decode(claims).ok())
+                .and_then(|claims| serde_json::from_slice::<serde_json::Value>(&claims).ok())
+                .map(|claims| claims["nonce"] == Self::NONCE);
+            let body = match (method, path) {
+                ("GET", "/.well-known/did.json") => serde_json::json!({
+                    "id": self.did,
+                    "alsoKnownAs": ["at://alice.test"],
+                    "service": [{
+                        "id": "#atproto_pds",
+                        "type": "AtprotoPersonalDataServer",
+                        "serviceEndpoint": self.origin,
+                    }],
+                }),
+                ("GET", "/.well-known/oauth-protected-resource") => {
+                    serde_json::json!({ "authorization_servers": [self.origin] })
+                }
+                ("GET", "/.well-known/oauth-authorization-server") => serde_json::json!({
+                    "issuer": self.origin,
+                    "authorization_endpoint": format!("{}/oauth/authorize", self.origin),
+                    "token_endpoint": format!("{}/oauth/token", self.origin),
+                    "pushed_authorization_request_endpoint": format!("{}/oauth/par", self.origin),
+                    "dpop_signing_alg_values_supported": ["ES256"],
+                }),
+                ("POST", "/oauth/par") => {
+                    self.par_requests
+                        .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    if nonce != Some(true) {
+                        return (
+                            "400 Bad Request",
+                            serde_json::json!({ "error": "use_dpop_nonce" }).to_string(),
+                        );
+                    }
+                    serde_json::json!({ "request_uri": "urn:ietf:params:oauth:request_uri:test", "expires_in": 60 })
+                }
+                ("POST", "/oauth/token") if nonce == Some(true) => serde_json::json!({
+                    "access_token": "access",
+                    "token_type": "DPoP",
+                    "scope": "atproto",
+                    "refresh_token": "refresh",
+                    "sub": self.sub.lock().unwrap().clone().unwrap_or_else(|| self.did.clone()),
+                }),
+                ("GET", "/xrpc/com.atproto.server.getSession")
+                    if nonce == Some(true)
+                        && headers.get("authorization").map(String::as_str)
+                            == Some("DPoP access") =>
+                {
+                    serde_json::json!({ "did": self.did })
+                }
+                _ => return ("401 Unauthorized", String::from("{}")),
+            };
+            ("200 OK", body.to_string())
+        }
+    }
+
+    fn atproto() -> AtprotoStrategy {
+        AtprotoStrategy::new(
+            "https://app.example.com/client-metadata.json",
+            vec![],
+            "https://app.example.com/callback",
+            "https://app.example.com/login",
+        )
+    }
+
+    fn atproto_callback(state: &CsrfToken, iss: &str) -> StateCode {
+        StateCode {
+            state: Some(state.clone()),
+            code: Some(String::from("code")),
+            params: HashMap::from([(String::from("iss"), iss.to_string())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn atproto_sign_in() {
+        let pds = FakePds::start().await;
+        let strategy = atproto();
+        let state = CsrfToken::new_random();
+        let url = strategy.authorize(&state, Some(&pds.did)).await.unwrap();
+        assert_eq!(url.path(), "/oauth/authorize");
+        assert!(url.query_pairs().any(|(key, value)| key == "request_uri"
+            && value == "urn:ietf:params:oauth:request_uri:test"));
+        // The first pushed request is answered with `use_dpop_nonce` and sent again with the nonce.
+        assert_eq!(
+            pds.par_requests.load(std::sync::atomic::Ordering::SeqCst),
+            2
+        );
+
+        let response = strategy
+            .exchange(&atproto_callback(&state, &pds.origin))
+            .await
+            .unwrap();
+        let profile = match response {
+            PassportResponse::Profile(profile) => profile,
+            PassportResponse::FailureRedirect(url) => panic!("Unexpected redirect to {}", url),
+        };
+        assert_eq!(profile["did"], pds.did);
+        assert_eq!(profile["handle"], "alice.test");
+        assert_eq!(profile["pds"], pds.origin);
+        assert_eq!(profile["issuer"], pds.origin);
+        assert_eq!(profile["access_token"], "access");
+        assert!(DpopKey::from_jwk(&profile["dpop_key"]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn atproto_rejects_unexpected_issuer() {
+        let pds = FakePds::start().await;
+        let strategy = atproto();
+        let state = CsrfToken::new_random();
+        strategy.authorize(&state, Some(&pds.did)).await.unwrap();
+        let error = strategy
+            .exchange(&atproto_callback(&state, "http://127.0.0.1:1"))
+            .await
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("unexpected issuer"));
+    }
+
+    #[tokio::test]
+    async fn atproto_rejects_account_of_another_server() {
+        let pds = FakePds::start().await;
+        let other = FakePds::start().await;
+        // Signed in from the server, the token claims an account whose PDS trusts another authorization server.
+        *pds.sub.lock().unwrap() = Some(other.did.clone());
+        let strategy = atproto();
+        let state = CsrfToken::new_random();
+        strategy.authorize(&state, Some(&pds.origin)).await.unwrap();
+        let error = strategy
+            .exchange(&atproto_callback(&state, &pds.origin))
+            .await
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("is not the authorization server of"));
+    }
+}